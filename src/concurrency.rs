@@ -5,23 +5,26 @@ use std::thread;
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 struct Worker {
-    id: usize,
     thread: thread::JoinHandle<()>,
 }
 
 impl Worker {
     fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || {
-            loop {
-                let message = receiver.lock().unwrap().recv();
-                match message {
-                    Ok(job) => job(),
-                    Err(_) => break,
+        // The name shows up in panic messages
+        let thread = thread::Builder::new()
+            .name(format!("worker-{}", id))
+            .spawn(move || {
+                loop {
+                    let message = receiver.lock().unwrap().recv();
+                    match message {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
                 }
-            }
-        });
+            })
+            .unwrap();
 
-        Worker { id, thread }
+        Worker { thread }
     }
 }
 
@@ -1,44 +1,93 @@
-mod encoding;
-mod handler;
+pub mod body;
+mod chunked;
+pub mod dictionary;
+pub mod encoding;
+pub mod etag;
+pub mod handler;
+pub mod header;
+#[cfg(feature = "compress")]
+mod lzw;
 pub mod method;
-mod middleware;
+pub mod middleware;
+mod range;
 pub mod request;
+pub mod router;
+pub mod safe_path;
+mod sendfile;
 pub mod server;
+pub mod static_files;
 pub mod status;
+pub mod url;
 
+use anyhow::anyhow;
+use body::Body;
+use header::HeaderMap;
 use status::Status;
-use std::collections::HashMap;
 
 const BUFFER_SIZE: usize = 1024;
 
 pub struct Response {
     status: Status,
-    headers: HashMap<String, String>,
-    content: Option<Vec<u8>>,
+    headers: HeaderMap,
+    content: Body,
+    compressible: bool,
+    /// For a 304, the response it stands for.
+    replaced: Option<Box<Response>>,
 }
 
 impl Response {
-    pub(crate) fn from_parts(
-        status: Status,
-        headers: HashMap<String, String>,
-        content: Option<Vec<u8>>,
-    ) -> Self {
+    pub fn from_parts(status: Status, headers: HeaderMap, content: impl Into<Body>) -> Self {
         Self {
             status,
             headers,
-            content,
+            content: content.into(),
+            compressible: true,
+            replaced: None,
         }
     }
+
+    /// Keeps the compression middleware from encoding this response.
+    pub fn without_compression(mut self) -> Self {
+        self.compressible = false;
+        self
+    }
+
+    /// Makes a 304 stand for `resp`, so `CompressionMw` gives it the validator
+    /// `resp` would have had.
+    pub fn replacing(mut self, resp: Response) -> Self {
+        self.replaced = Some(Box::new(resp));
+        self
+    }
 }
 
 pub fn ok() -> Response {
-    Response::from_parts(Status::OK, HashMap::new(), None)
+    Response::from_parts(Status::OK, HeaderMap::new(), None)
 }
 
 pub fn not_found() -> Response {
-    Response::from_parts(Status::NOT_FOUND, HashMap::new(), None)
+    Response::from_parts(Status::NOT_FOUND, HeaderMap::new(), None)
+}
+
+pub fn forbidden() -> Response {
+    Response::from_parts(Status::FORBIDDEN, HeaderMap::new(), None)
 }
 
 pub fn bad_request() -> Response {
-    Response::from_parts(Status::BAD_REQUEST, HashMap::new(), None)
+    Response::from_parts(Status::BAD_REQUEST, HeaderMap::new(), None)
+}
+
+/// Splits a `name: value` field line into a name and a trimmed value.
+fn parse_header_line(line: &str) -> anyhow::Result<(String, String)> {
+    let (k, v) = line
+        .trim_ascii()
+        .split_once(":")
+        .ok_or(anyhow!("Invalid header"))?;
+    Ok((String::from(k), String::from(v.trim_ascii())))
+}
+
+/// `token` as defined in RFC 9110, section 5.6.2.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
 }
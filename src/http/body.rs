@@ -0,0 +1,123 @@
+use crate::http::header::HeaderMap;
+use crate::http::sendfile::FileSink;
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Produces a streamed body by writing into the connection and returns the trailer fields.
+pub type StreamFn = Box<dyn FnOnce(&mut dyn Write) -> std::io::Result<HeaderMap> + Send>;
+
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    /// Sent straight from the file, with `sendfile(2)` where available.
+    File(FileBody),
+    /// Sent with `Transfer-Encoding: chunked` as the producer writes it.
+    Stream(StreamFn),
+}
+
+impl Body {
+    pub fn stream<F>(f: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write) -> std::io::Result<HeaderMap> + Send + 'static,
+    {
+        Body::Stream(Box::new(f))
+    }
+
+    pub fn from_chunks<I>(chunks: I) -> Self
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        let chunks = chunks.into_iter();
+        Self::stream(move |w| {
+            for chunk in chunks {
+                w.write_all(&chunk)?;
+                w.flush()?;
+            }
+            Ok(HeaderMap::new())
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Body::Empty)
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl From<Option<Vec<u8>>> for Body {
+    fn from(content: Option<Vec<u8>>) -> Self {
+        content.map(Body::Bytes).unwrap_or(Body::Empty)
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(content: Vec<u8>) -> Self {
+        Body::Bytes(content)
+    }
+}
+
+impl From<FileBody> for Body {
+    fn from(file: FileBody) -> Self {
+        Body::File(file)
+    }
+}
+
+/// A region of an open file.
+pub struct FileBody {
+    file: File,
+    offset: u64,
+    len: u64,
+}
+
+impl FileBody {
+    /// The whole file, with the length it has now.
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self::with_range(file, 0, len))
+    }
+
+    pub fn with_range(file: File, offset: u64, len: u64) -> Self {
+        Self { file, offset, len }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the region into memory.
+    pub fn read_to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.offset))?;
+
+        let mut content = Vec::with_capacity(self.len as usize);
+        file.take(self.len).read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    pub(crate) fn write_to(&self, w: &mut (impl FileSink + ?Sized)) -> io::Result<()> {
+        w.write_file(&self.file, self.offset, self.len)
+    }
+}
+
+impl Debug for Body {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Body::Empty => write!(f, "Empty"),
+            Body::Bytes(b) => write!(f, "Bytes({} bytes)", b.len()),
+            Body::File(b) => write!(f, "File({} bytes)", b.len()),
+            Body::Stream(_) => write!(f, "Stream"),
+        }
+    }
+}
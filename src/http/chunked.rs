@@ -0,0 +1,189 @@
+use crate::http::header::HeaderMap;
+use crate::http::parse_header_line;
+use anyhow::{Context, bail};
+use std::cmp::min;
+use std::io;
+use std::io::{BufRead, Write};
+
+/// Incrementally decodes a chunked body, so it can be read in parts from the connection.
+#[derive(Debug, Default)]
+pub struct ChunkedDecoder {
+    chunk_remaining: usize,
+    done: bool,
+    trailers: HeaderMap,
+}
+
+impl ChunkedDecoder {
+    pub fn read(&mut self, rdr: &mut (impl BufRead + ?Sized), buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.chunk_remaining == 0 {
+            let mut line_buf = String::with_capacity(16);
+            if rdr.read_line(&mut line_buf)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            self.chunk_remaining = parse_chunk_size(&line_buf).map_err(io::Error::other)?;
+            if self.chunk_remaining == 0 {
+                self.read_trailers(rdr)?;
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let to_read = min(buf.len(), self.chunk_remaining);
+        let n = rdr.read(&mut buf[..to_read])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.chunk_remaining -= n;
+
+        if self.chunk_remaining == 0 {
+            let mut crlf = [0u8; 2];
+            rdr.read_exact(&mut crlf)?;
+            if &crlf != b"\r\n" {
+                return Err(io::Error::other("Missing CRLF after chunk data"));
+            }
+        }
+
+        Ok(n)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+
+    fn read_trailers(&mut self, rdr: &mut (impl BufRead + ?Sized)) -> io::Result<()> {
+        let mut line_buf = String::with_capacity(64);
+        loop {
+            line_buf.clear();
+            let n = rdr.read_line(&mut line_buf)?;
+
+            if n == 0 || line_buf.trim_ascii().is_empty() {
+                return Ok(());
+            }
+
+            let (k, v) = parse_header_line(&line_buf).map_err(io::Error::other)?;
+            self.trailers.try_append(k, v).map_err(io::Error::other)?;
+        }
+    }
+}
+
+/// Parses a `chunk-size [ chunk-ext ] CRLF` line, ignoring any extensions.
+fn parse_chunk_size(line: &str) -> anyhow::Result<usize> {
+    let size = line
+        .split_once(';')
+        .map(|(size, _ext)| size)
+        .unwrap_or(line)
+        .trim_ascii();
+
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("Invalid chunk size");
+    }
+
+    usize::from_str_radix(size, 16).context("Invalid chunk size")
+}
+
+/// Frames every write as a single chunk; `finish` emits the last chunk and trailers.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    pub fn finish(mut self, trailers: &HeaderMap) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n")?;
+        for (k, v) in trailers.iter() {
+            self.inner
+                .write_all(format!("{}: {}\r\n", k, v).as_bytes())?;
+        }
+        self.inner.write_all(b"\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A zero-sized chunk would terminate the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.inner
+            .write_all(format!("{:x}\r\n", buf.len()).as_bytes())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_chunked_body(rdr: &mut impl BufRead) -> io::Result<(Vec<u8>, HeaderMap)> {
+        let mut decoder = ChunkedDecoder::default();
+        let mut content = Vec::new();
+        let mut buf = [0u8; 3];
+        loop {
+            let n = decoder.read(rdr, &mut buf)?;
+            if n == 0 {
+                return Ok((content, decoder.trailers));
+            }
+            content.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn test_read_chunked_body() {
+        let raw = b"4\r\nWiki\r\n7;name=val\r\npedia i\r\nB\r\nn \r\nchunks.\r\n0\r\nExpires: never\r\n\r\nGET";
+        let mut rdr = &raw[..];
+
+        let (content, trailers) = read_chunked_body(&mut rdr).unwrap();
+
+        assert_eq!(content, b"Wikipedia in \r\nchunks.");
+        assert_eq!(trailers.get("Expires"), Some("never"));
+        assert_eq!(rdr, b"GET");
+    }
+
+    #[test]
+    fn test_chunked_writer_roundtrip() {
+        let mut wr = ChunkedWriter::new(Vec::new());
+        wr.write_all(b"Wiki").unwrap();
+        wr.write_all(b"").unwrap();
+        wr.write_all(b"pedia").unwrap();
+        let trailers = HeaderMap::from([("expires", "never")]);
+        let raw = wr.finish(&trailers).unwrap();
+
+        assert_eq!(
+            raw,
+            b"4\r\nWiki\r\n5\r\npedia\r\n0\r\nexpires: never\r\n\r\n"
+        );
+
+        let (content, decoded_trailers) = read_chunked_body(&mut &raw[..]).unwrap();
+        assert_eq!(content, b"Wikipedia");
+        assert_eq!(decoded_trailers, trailers);
+    }
+
+    #[test]
+    fn test_read_chunked_body_errors() {
+        assert!(read_chunked_body(&mut &b"zz\r\n"[..]).is_err());
+        assert!(read_chunked_body(&mut &b"4\r\nWikiXX0\r\n\r\n"[..]).is_err());
+        assert!(read_chunked_body(&mut &b"4\r\nWi"[..]).is_err());
+        assert!(read_chunked_body(&mut &b"ffffffffffffffffff\r\n"[..]).is_err());
+    }
+}
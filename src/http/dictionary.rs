@@ -0,0 +1,419 @@
+use crate::http::encoding::Encoding;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::io;
+#[cfg(feature = "zstd")]
+use std::io::Write;
+use std::sync::{Arc, RwLock};
+
+const DEFAULT_MAX_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Start of a `dcb` body, followed by the SHA-256 of the dictionary (RFC 9842, section 4.1).
+#[cfg(feature = "br")]
+const DCB_MAGIC: [u8; 4] = [0xff, 0x44, 0x43, 0x42];
+
+/// Start of a `dcz` body: a skippable zstd frame holding the SHA-256 of the
+/// dictionary (RFC 9842, section 4.2).
+#[cfg(feature = "zstd")]
+const DCZ_MAGIC: [u8; 8] = [0x5e, 0x2a, 0x4d, 0x18, 0x20, 0x00, 0x00, 0x00];
+
+/// Codings that compress with a dictionary, in order of preference.
+pub const SUPPORTED: &[Encoding] = &[
+    #[cfg(feature = "br")]
+    Encoding::Dcb,
+    #[cfg(feature = "zstd")]
+    Encoding::Dcz,
+];
+
+/// A compression dictionary (RFC 9842): the content of a response that clients
+/// keep, and use for later responses whose path matches the pattern.
+#[derive(Debug)]
+pub struct Dictionary {
+    content: Vec<u8>,
+    hash: [u8; 32],
+    match_pattern: String,
+    id: Option<String>,
+    etag: Option<String>,
+}
+
+impl Dictionary {
+    /// `match_pattern` is a path in which `*` stands for any sequence of
+    /// characters, like `/js/app.*.js`.
+    pub fn new(content: impl Into<Vec<u8>>, match_pattern: impl Into<String>) -> Self {
+        let content = content.into();
+        Self {
+            hash: Sha256::digest(&content).into(),
+            content,
+            match_pattern: match_pattern.into(),
+            id: None,
+            etag: None,
+        }
+    }
+
+    /// An identifier clients send back in `Dictionary-ID`.
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// The strong `ETag` of the response the dictionary is made from.
+    pub fn etag(mut self, etag: impl Into<String>) -> Self {
+        self.etag = Some(etag.into());
+        self
+    }
+
+    pub fn hash(&self) -> &[u8; 32] {
+        &self.hash
+    }
+
+    pub fn len(&self) -> usize {
+        self.content.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.content.is_empty()
+    }
+
+    /// Whether the dictionary applies to a response for `path`.
+    pub fn matches(&self, path: &str) -> bool {
+        pattern_matches(&self.match_pattern, path)
+    }
+
+    /// Value of the `Use-As-Dictionary` header of the response the dictionary comes from.
+    pub fn use_as_dictionary(&self) -> String {
+        let mut value = format!("match={}", sf_string(&self.match_pattern));
+        if let Some(id) = &self.id {
+            value.push_str(&format!(", id={}", sf_string(id)));
+        }
+        value
+    }
+
+    /// Compresses `content` as `dcb` or `dcz`. Levels are those of br and zstd.
+    #[cfg_attr(not(any(feature = "br", feature = "zstd")), allow(unused_variables))]
+    pub fn encode(&self, encoding: Encoding, level: u32, content: &[u8]) -> io::Result<Vec<u8>> {
+        match encoding {
+            #[cfg(feature = "br")]
+            Encoding::Dcb => self.encode_br(level, content),
+            #[cfg(feature = "zstd")]
+            Encoding::Dcz => self.encode_zstd(level, content),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("can't encode {} with a dictionary", encoding),
+            )),
+        }
+    }
+
+    #[cfg(feature = "br")]
+    fn encode_br(&self, level: u32, content: &[u8]) -> io::Result<Vec<u8>> {
+        // The dictionary is placed before the content, so both have to fit in
+        // the window, which is at most 16 MiB
+        let lgwin = window_log(self.content.len() + content.len() + 16).max(10);
+        if lgwin > 24 {
+            return Err(io::Error::other(
+                "dictionary and content don't fit in a brotli window",
+            ));
+        }
+        let params = brotli::enc::BrotliEncoderParams {
+            quality: level.min(11) as i32,
+            lgwin: lgwin as i32,
+            ..Default::default()
+        };
+
+        let mut out = DCB_MAGIC.to_vec();
+        out.extend_from_slice(&self.hash);
+        brotli::BrotliCompressCustomIoCustomDict(
+            &mut brotli::IoReaderWrapper(&mut &content[..]),
+            &mut brotli::IoWriterWrapper(&mut out),
+            &mut [0; 4096],
+            &mut [0; 4096],
+            &params,
+            brotli::enc::StandardAlloc::default(),
+            &mut |_: &mut _, _: &mut _, _, _: &mut _| (),
+            &self.content,
+            io::Error::from(io::ErrorKind::UnexpectedEof),
+        )?;
+        Ok(out)
+    }
+
+    #[cfg(feature = "zstd")]
+    fn encode_zstd(&self, level: u32, content: &[u8]) -> io::Result<Vec<u8>> {
+        // Clients accept windows up to 8 MiB, or 1.25 times the dictionary up to 128 MiB
+        let max_window = (8 << 20).max(self.content.len() / 4 * 5).min(128 << 20);
+        let window_log =
+            window_log(self.content.len() + content.len()).clamp(10, max_window.ilog2());
+
+        let mut out = DCZ_MAGIC.to_vec();
+        out.extend_from_slice(&self.hash);
+        let mut encoder = zstd::stream::write::Encoder::with_ref_prefix(
+            out,
+            level.clamp(1, 22) as i32,
+            &self.content,
+        )?;
+        encoder.set_parameter(zstd::stream::raw::CParameter::WindowLog(window_log))?;
+        encoder.set_pledged_src_size(Some(content.len() as u64))?;
+        encoder.write_all(content)?;
+        encoder.finish()
+    }
+}
+
+/// Dictionaries the compression middleware can use, and the responses that are
+/// offered to clients as dictionaries.
+///
+/// Designated responses are added to the store as they are sent, so that the
+/// next version of a resource can be compressed against the one clients have.
+/// When the store is full, the dictionaries offered least recently are dropped.
+pub struct DictionaryStore {
+    designations: Vec<(String, String)>,
+    dictionaries: RwLock<VecDeque<Arc<Dictionary>>>,
+    max_size: u64,
+}
+
+impl Default for DictionaryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DictionaryStore {
+    pub fn new() -> Self {
+        Self {
+            designations: Vec::new(),
+            dictionaries: RwLock::new(VecDeque::new()),
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+
+    /// Offers responses for paths matching `path` as dictionaries for paths
+    /// matching `match_pattern`. Both may use `*` wildcards.
+    pub fn designate(mut self, path: &str, match_pattern: &str) -> Self {
+        self.designations
+            .push((path.to_string(), match_pattern.to_string()));
+        self
+    }
+
+    /// Limit on the total size of the stored dictionaries, 32 MiB by default.
+    pub fn max_size(mut self, size: u64) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    /// Stores a dictionary, like a resource clients got from an earlier deployment.
+    /// Returns the stored dictionary, which is the existing one if it was known.
+    pub fn add(&self, dictionary: Dictionary) -> Arc<Dictionary> {
+        self.insert(Arc::new(dictionary))
+    }
+
+    /// The stored dictionary made from the response with the strong `etag`, which
+    /// spares hashing the response again when it is offered once more.
+    pub fn offered(&self, etag: &str, match_pattern: &str) -> Option<Arc<Dictionary>> {
+        let dictionaries = self.dictionaries.read().unwrap();
+        let i = dictionaries
+            .iter()
+            .rposition(|d| d.etag.as_deref() == Some(etag) && d.match_pattern == match_pattern)?;
+        let dictionary = Arc::clone(&dictionaries[i]);
+        let newest = i + 1 == dictionaries.len();
+        drop(dictionaries);
+
+        // Offering it again makes it the most recent one
+        if newest {
+            Some(dictionary)
+        } else {
+            Some(self.insert(dictionary))
+        }
+    }
+
+    fn insert(&self, dictionary: Arc<Dictionary>) -> Arc<Dictionary> {
+        let mut dictionaries = self.dictionaries.write().unwrap();
+
+        let known = dictionaries
+            .iter()
+            .position(|d| d.hash == dictionary.hash && d.match_pattern == dictionary.match_pattern);
+        let dictionary = known
+            .and_then(|i| dictionaries.remove(i))
+            .unwrap_or(dictionary);
+        if dictionary.len() as u64 > self.max_size {
+            return dictionary;
+        }
+
+        dictionaries.push_back(Arc::clone(&dictionary));
+        let mut total: u64 = dictionaries.iter().map(|d| d.len() as u64).sum();
+        while total > self.max_size {
+            let oldest = dictionaries.pop_front().unwrap();
+            total -= oldest.len() as u64;
+        }
+        dictionary
+    }
+
+    /// Looks up the dictionary named by an `Available-Dictionary` header, a
+    /// structured field byte sequence holding its SHA-256.
+    pub fn find(&self, available_dictionary: &str) -> Option<Arc<Dictionary>> {
+        let encoded = available_dictionary
+            .trim()
+            .strip_prefix(':')?
+            .strip_suffix(':')?;
+        let hash = STANDARD.decode(encoded).ok()?;
+
+        self.dictionaries
+            .read()
+            .unwrap()
+            .iter()
+            .find(|d| d.hash[..] == hash[..])
+            .cloned()
+    }
+
+    /// The match pattern of the dictionary that a response for `path` provides.
+    pub fn designation(&self, path: &str) -> Option<&str> {
+        self.designations
+            .iter()
+            .find(|(p, _)| pattern_matches(p, path))
+            .map(|(_, m)| m.as_str())
+    }
+
+    /// Whether a response for `path` may be compressed with a dictionary, so
+    /// that it varies on `Available-Dictionary`.
+    pub fn covers(&self, path: &str) -> bool {
+        self.designations
+            .iter()
+            .any(|(_, m)| pattern_matches(m, path))
+            || self
+                .dictionaries
+                .read()
+                .unwrap()
+                .iter()
+                .any(|d| d.matches(path))
+    }
+}
+
+/// Matches a path against a pattern in which `*` stands for any sequence of
+/// characters; the subset of URL patterns (RFC 9842, section 2.1.1) we use.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = path.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Serializes a structured field string (RFC 8941, section 3.3.3).
+fn sf_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The smallest window log that holds `size` bytes.
+#[cfg(any(feature = "br", feature = "zstd"))]
+fn window_log(size: usize) -> u32 {
+    usize::BITS - size.saturating_sub(1).leading_zeros()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pattern_matches() {
+        assert!(pattern_matches("/js/app.*.js", "/js/app.1a2b.js"));
+        assert!(pattern_matches("/js/*", "/js/"));
+        assert!(pattern_matches("/a/*/b/*", "/a/x/b/y/z"));
+        assert!(!pattern_matches("/js/app.*.js", "/js/app.js"));
+        assert!(!pattern_matches("/js/app.js", "/js/app.js.map"));
+        assert!(!pattern_matches("/a*a", "/a"));
+    }
+
+    #[test]
+    fn test_store() {
+        let store = DictionaryStore::new()
+            .designate("/js/app.*.js", "/js/app.*.js")
+            .max_size(10);
+        let v1 = store.add(Dictionary::new(b"version 1".to_vec(), "/js/app.*.js").id("v1"));
+        assert_eq!(v1.use_as_dictionary(), "match=\"/js/app.*.js\", id=\"v1\"");
+
+        let header = format!(":{}:", STANDARD.encode(v1.hash()));
+        assert!(store.find(&header).is_some());
+        assert!(store.find(":AAAA:").is_none());
+        assert!(store.covers("/js/app.2.js"));
+        assert!(!store.covers("/index.html"));
+        assert_eq!(store.designation("/js/app.2.js"), Some("/js/app.*.js"));
+
+        // Adding the next version evicts the first one
+        store.add(Dictionary::new(b"version 2".to_vec(), "/js/app.*.js"));
+        assert!(store.find(&header).is_none());
+    }
+
+    #[test]
+    fn test_offered() {
+        let store = DictionaryStore::new().max_size(20);
+        let v1 = store.add(Dictionary::new(b"version 1".to_vec(), "/*").etag("\"1\""));
+        let v2 = store.add(Dictionary::new(b"version 2".to_vec(), "/*").etag("\"2\""));
+        assert!(store.offered("\"3\"", "/*").is_none());
+        assert!(store.offered("\"1\"", "/js/*").is_none());
+
+        // Offering the first version again keeps it over the second
+        assert!(Arc::ptr_eq(&store.offered("\"1\"", "/*").unwrap(), &v1));
+        store.add(Dictionary::new(b"version 3".to_vec(), "/*"));
+        assert!(store.offered("\"1\"", "/*").is_some());
+        assert!(store.offered("\"2\"", "/*").is_none());
+        let header = format!(":{}:", STANDARD.encode(v2.hash()));
+        assert!(store.find(&header).is_none());
+    }
+
+    #[test]
+    fn test_encode() {
+        let dictionary = Dictionary::new(
+            b"function hello() { return 'hello world'; }\n".repeat(20),
+            "/*",
+        );
+        let content = b"function hello() { return 'hello world!'; }\n".repeat(20);
+
+        #[cfg(feature = "br")]
+        {
+            let dcb = dictionary.encode(Encoding::Dcb, 4, &content).unwrap();
+            assert_eq!(dcb[..4], DCB_MAGIC);
+            assert_eq!(&dcb[4..36], dictionary.hash());
+
+            let mut decoded = Vec::new();
+            io::Read::read_to_end(
+                &mut brotli::Decompressor::new_with_custom_dict(
+                    &dcb[36..],
+                    4096,
+                    dictionary.content.clone().into(),
+                ),
+                &mut decoded,
+            )
+            .unwrap();
+            assert_eq!(decoded, content);
+        }
+
+        #[cfg(feature = "zstd")]
+        {
+            let dcz = dictionary.encode(Encoding::Dcz, 3, &content).unwrap();
+            assert_eq!(dcz[..8], DCZ_MAGIC);
+            assert_eq!(&dcz[8..40], dictionary.hash());
+
+            let mut decoded = Vec::new();
+            io::Read::read_to_end(
+                &mut zstd::stream::read::Decoder::with_ref_prefix(&dcz[40..], &dictionary.content)
+                    .unwrap(),
+                &mut decoded,
+            )
+            .unwrap();
+            assert_eq!(decoded, content);
+            // The dictionary helps: the encoded content is smaller than without it
+            assert!(dcz.len() - 40 < zstd::encode_all(&content[..], 3).unwrap().len());
+        }
+
+        assert!(dictionary.encode(Encoding::Gzip, 6, &content).is_err());
+    }
+}
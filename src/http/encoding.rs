@@ -1,10 +1,13 @@
+use crate::http::is_token;
+use anyhow::{anyhow, bail};
+use std::str::FromStr;
 use strum::{Display, EnumString, IntoStaticStr};
 
 #[derive(EnumString, IntoStaticStr, Debug, PartialEq, Eq, Hash, Clone, Copy, Display)]
 pub enum Encoding {
-    #[strum(serialize = "gzip")]
+    #[strum(to_string = "gzip", serialize = "x-gzip")]
     Gzip,
-    #[strum(serialize = "compress")]
+    #[strum(to_string = "compress", serialize = "x-compress")]
     Compress,
     #[strum(serialize = "deflate")]
     Deflate,
@@ -28,3 +31,119 @@ pub struct EncodingVal {
     pub encoding: Encoding,
     pub quality: f32,
 }
+
+/// A parsed `Accept-Encoding` field (RFC 9110, section 12.5.3). Unknown codings are dropped.
+#[derive(Debug, Default, PartialEq)]
+pub struct AcceptEncoding {
+    values: Vec<EncodingVal>,
+}
+
+impl AcceptEncoding {
+    pub fn parse(header: &str) -> anyhow::Result<Self> {
+        let mut values = Vec::new();
+        for element in header.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = element.split(';').map(str::trim);
+            let coding = parts.next().unwrap_or_default();
+            if !is_token(coding) {
+                bail!("Invalid content coding: {:?}", coding);
+            }
+
+            let mut quality = 1.0;
+            for param in parts {
+                let (name, value) = param
+                    .split_once('=')
+                    .ok_or(anyhow!("Invalid parameter: {:?}", param))?;
+                if name.trim().eq_ignore_ascii_case("q") {
+                    quality = parse_qvalue(value.trim())?;
+                }
+            }
+
+            if let Ok(encoding) = Encoding::from_str(&coding.to_ascii_lowercase()) {
+                values.push(EncodingVal { encoding, quality });
+            }
+        }
+        Ok(Self { values })
+    }
+
+    /// Quality of `encoding` as listed, or as covered by `*`.
+    pub fn quality(&self, encoding: Encoding) -> Option<f32> {
+        let find = |e: Encoding| self.values.iter().find(|v| v.encoding == e);
+        find(encoding)
+            .or_else(|| find(Encoding::Any))
+            .map(|v| v.quality)
+    }
+
+    /// The acceptable coding with the highest quality out of `supported`, which is
+    /// in order of preference. Identity wins only if it is listed with a higher
+    /// quality or nothing else is acceptable, and `None` means it is refused too.
+    pub fn negotiate(&self, supported: &[Encoding]) -> Option<Encoding> {
+        let mut best: Option<(Encoding, f32)> = None;
+        for &encoding in supported.iter().chain([&Encoding::Identity]) {
+            if let Some(q) = self.quality(encoding) {
+                if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                    best = Some((encoding, q));
+                }
+            }
+        }
+
+        match best {
+            Some((encoding, _)) => Some(encoding),
+            // Identity is acceptable unless excluded explicitly
+            None if self.quality(Encoding::Identity).is_none() => Some(Encoding::Identity),
+            None => None,
+        }
+    }
+}
+
+/// `qvalue` as defined in RFC 9110, section 12.4.2.
+fn parse_qvalue(s: &str) -> anyhow::Result<f32> {
+    let valid = match s.split_once('.') {
+        Some((int, frac)) => {
+            (int == "0" || (int == "1" && frac.bytes().all(|b| b == b'0')))
+                && frac.len() <= 3
+                && frac.bytes().all(|b| b.is_ascii_digit())
+        }
+        None => s == "0" || s == "1",
+    };
+    if !valid {
+        bail!("Invalid qvalue: {:?}", s);
+    }
+    Ok(s.parse()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Encoding::{Br, Gzip, Identity};
+
+    #[test]
+    fn test_parse() {
+        let a = AcceptEncoding::parse("gzip;q=0.5, BR , foo, identity; q=0,").unwrap();
+        assert_eq!(a.quality(Gzip), Some(0.5));
+        assert_eq!(a.quality(Br), Some(1.0));
+        assert_eq!(a.quality(Identity), Some(0.0));
+        assert_eq!(a.quality(Encoding::Zstd), None);
+
+        assert!(AcceptEncoding::parse("gzip;q=2").is_err());
+        assert!(AcceptEncoding::parse("gzip;q=0.1234").is_err());
+        assert!(AcceptEncoding::parse("gzip;q").is_err());
+        assert!(AcceptEncoding::parse("gz ip").is_err());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let negotiate = |h: &str| AcceptEncoding::parse(h).unwrap().negotiate(&[Br, Gzip]);
+
+        assert_eq!(negotiate("gzip, br"), Some(Br));
+        assert_eq!(negotiate("gzip;q=1, br;q=0.5"), Some(Gzip));
+        assert_eq!(negotiate("gzip;q=0"), Some(Identity));
+        assert_eq!(negotiate("*"), Some(Br));
+        assert_eq!(negotiate("*;q=0.5, br;q=0"), Some(Gzip));
+        assert_eq!(negotiate("gzip;q=0.5, identity"), Some(Identity));
+        assert_eq!(negotiate(""), Some(Identity));
+        assert_eq!(negotiate("deflate"), Some(Identity));
+        assert_eq!(negotiate("identity;q=0"), None);
+        assert_eq!(negotiate("*;q=0"), None);
+        assert_eq!(negotiate("*;q=0, identity;q=0.1"), Some(Identity));
+    }
+}
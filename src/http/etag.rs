@@ -0,0 +1,144 @@
+use crate::http::encoding::Encoding;
+use std::fmt::{Display, Formatter};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::str::FromStr;
+
+/// An entity tag (RFC 9110, section 8.8.3).
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityTag {
+    pub weak: bool,
+    pub tag: String,
+}
+
+impl EntityTag {
+    pub fn strong(tag: impl Into<String>) -> Self {
+        Self {
+            weak: false,
+            tag: tag.into(),
+        }
+    }
+
+    pub fn weak(tag: impl Into<String>) -> Self {
+        Self {
+            weak: true,
+            tag: tag.into(),
+        }
+    }
+
+    /// Strong tag derived from a hash of the content.
+    pub fn from_content(content: &[u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        Self::strong(format!("{:016x}", hasher.finish()))
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (weak, opaque) = match s.strip_prefix("W/") {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+
+        let tag = opaque.strip_prefix('"')?.strip_suffix('"')?;
+        if tag.contains('"') {
+            return None;
+        }
+        Some(Self {
+            weak,
+            tag: tag.to_string(),
+        })
+    }
+
+    /// The tag of the representation encoded with `coding`, which is a different
+    /// sequence of bytes and can't share the tag of the original.
+    pub fn with_coding(&self, coding: Encoding) -> Self {
+        Self {
+            weak: self.weak,
+            tag: format!("{}-{}", self.tag, coding),
+        }
+    }
+
+    /// The tag `with_coding` was called on, if this is one of its results.
+    pub fn without_coding(&self) -> Option<Self> {
+        let (tag, coding) = self.tag.rsplit_once('-')?;
+        match Encoding::from_str(coding) {
+            Ok(Encoding::Identity | Encoding::Any) | Err(_) => None,
+            Ok(_) => Some(Self {
+                weak: self.weak,
+                tag: tag.to_string(),
+            }),
+        }
+    }
+
+    pub fn strong_eq(&self, other: &EntityTag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    pub fn weak_eq(&self, other: &EntityTag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl Display for EntityTag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.weak {
+            write!(f, "W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// Whether a list of entity tags, as in `If-Match` or `If-None-Match`, contains
+/// `current`. The tag of an encoded form of `current` matches too, as the client
+/// only knows the tags `CompressionMw` sent. Members that can't be parsed never match.
+pub fn list_contains(header: &str, current: &EntityTag, weak: bool) -> bool {
+    let matches = |t: &EntityTag| {
+        if weak {
+            t.weak_eq(current)
+        } else {
+            t.strong_eq(current)
+        }
+    };
+    header
+        .split(',')
+        .filter_map(EntityTag::parse)
+        .any(|t| matches(&t) || t.without_coding().is_some_and(|t| matches(&t)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(EntityTag::parse("\"abc\""), Some(EntityTag::strong("abc")));
+        assert_eq!(
+            EntityTag::parse(" W/\"abc\" "),
+            Some(EntityTag::weak("abc"))
+        );
+        assert_eq!(EntityTag::parse("abc"), None);
+        assert_eq!(EntityTag::parse("\"a\"b\""), None);
+        assert_eq!(EntityTag::weak("x").to_string(), "W/\"x\"");
+    }
+
+    #[test]
+    fn test_list_contains() {
+        let strong = EntityTag::strong("1");
+        let weak = EntityTag::weak("1");
+
+        assert!(list_contains("\"0\", \"1\"", &strong, false));
+        assert!(!list_contains("W/\"1\"", &strong, false));
+        assert!(list_contains("W/\"1\"", &strong, true));
+        assert!(!list_contains("\"1\"", &weak, false));
+        assert!(!list_contains("*", &strong, true));
+
+        let gzip = strong.with_coding(Encoding::Gzip);
+        assert_eq!(gzip.to_string(), "\"1-gzip\"");
+        assert!(list_contains("\"1-gzip\"", &strong, false));
+        assert!(list_contains("\"0\", \"1-br\"", &strong, false));
+        assert!(list_contains("\"1-gzip\"", &gzip, false));
+        assert!(!list_contains("\"1-identity\"", &strong, false));
+        assert!(!list_contains("\"1-other\"", &strong, true));
+        assert!(!list_contains("\"1\"", &gzip, true));
+    }
+}
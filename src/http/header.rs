@@ -0,0 +1,198 @@
+use crate::http::is_token;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum HeaderError {
+    #[error("invalid header name {0:?}")]
+    InvalidName(String),
+    #[error("invalid value for header {0:?}")]
+    InvalidValue(String),
+}
+
+/// Header fields in insertion order. Names are matched case-insensitively and
+/// keep the case they were inserted with; a name may have several values.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the first value of the field.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Replaces all values of the field, keeping the position of the first one.
+    ///
+    /// Panics if the name or value is invalid; use `try_insert` for untrusted input.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.try_insert(name, value).unwrap()
+    }
+
+    /// Adds a value without removing existing ones.
+    ///
+    /// Panics if the name or value is invalid; use `try_append` for untrusted input.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.try_append(name, value).unwrap()
+    }
+
+    pub fn try_insert(
+        &mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), HeaderError> {
+        let (name, value) = Self::validate(name.into(), value.into())?;
+
+        match self
+            .entries
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(&name))
+        {
+            Some(pos) => {
+                let mut i = 0;
+                self.entries.retain(|(k, _)| {
+                    let keep = i <= pos || !k.eq_ignore_ascii_case(&name);
+                    i += 1;
+                    keep
+                });
+                self.entries[pos] = (name, value);
+            }
+            None => self.entries.push((name, value)),
+        }
+        Ok(())
+    }
+
+    pub fn try_append(
+        &mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), HeaderError> {
+        let entry = Self::validate(name.into(), value.into())?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Removes all values of the field and returns the first one.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let mut removed = None;
+        self.entries.retain(|(k, v)| {
+            if !k.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            if removed.is_none() {
+                removed = Some(v.clone());
+            }
+            false
+        });
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn validate(name: String, value: String) -> Result<(String, String), HeaderError> {
+        if !is_token(&name) {
+            return Err(HeaderError::InvalidName(name));
+        }
+        // Field values can't contain CR, LF or NUL (RFC 9110, section 5.5)
+        if value.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0')) {
+            return Err(HeaderError::InvalidValue(name));
+        }
+        Ok((name, value))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>, const N: usize> From<[(K, V); N]> for HeaderMap {
+    fn from(entries: [(K, V); N]) -> Self {
+        entries.into_iter().collect()
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for HeaderMap {
+    /// Panics on invalid names or values, like `append`.
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut headers = HeaderMap::new();
+        for (k, v) in iter {
+            headers.append(k, v);
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_multiple_values_in_order() {
+        let mut headers = HeaderMap::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Content-Type", "text/plain");
+        headers.append("set-cookie", "b=2");
+
+        assert_eq!(headers.get("SET-COOKIE"), Some("a=1"));
+        assert_eq!(
+            headers.get_all("set-cookie").collect::<Vec<_>>(),
+            vec!["a=1", "b=2"]
+        );
+        assert_eq!(
+            headers.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+            vec!["Set-Cookie", "Content-Type", "set-cookie"]
+        );
+    }
+
+    #[test]
+    fn test_insert_replaces_all_values() {
+        let mut headers = HeaderMap::from([("A", "1"), ("B", "2"), ("a", "3")]);
+        headers.insert("a", "4");
+
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            vec![("a", "4"), ("B", "2")]
+        );
+        assert_eq!(headers.remove("A"), Some("4".to_string()));
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut headers = HeaderMap::new();
+
+        assert_eq!(
+            headers.try_append("Bad Name", "x"),
+            Err(HeaderError::InvalidName("Bad Name".to_string()))
+        );
+        assert_eq!(
+            headers.try_append("X-Injected", "a\r\nSet-Cookie: b"),
+            Err(HeaderError::InvalidValue("X-Injected".to_string()))
+        );
+        assert!(headers.is_empty());
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+const MAGIC: [u8; 2] = [0x1f, 0x9d];
+/// Flag in the third header byte: code 256 clears the table.
+const BLOCK_MODE: u8 = 0x80;
+const BITS_MASK: u8 = 0x1f;
+
+pub const MIN_BITS: u32 = 9;
+pub const MAX_BITS: u32 = 16;
+
+const CLEAR: u32 = 256;
+const FIRST: u32 = 257;
+
+/// Output is passed on once this much has been decoded, to bound memory use.
+const OUT_CHUNK: usize = 64 * 1024;
+
+/// The largest code at the current width, before the width grows. As in
+/// `compress`, 9 bit codes always grow, even when 9 is the maximum.
+fn max_code(n_bits: u32, max_bits: u32) -> u32 {
+    if n_bits == max_bits && n_bits > MIN_BITS {
+        1 << max_bits
+    } else {
+        (1 << n_bits) - 1
+    }
+}
+
+/// Codes are written in groups of eight, so a group takes whole bytes. When the
+/// width changes or the table is cleared, `compress` fills the rest of the
+/// group, and decoders skip it. Returns the number of bits to fill.
+fn group_padding(group_codes: u32, n_bits: u32) -> u32 {
+    (8 - group_codes % 8) % 8 * n_bits
+}
+
+/// Compresses into `W` as data is written, in the format of the Unix `compress`
+/// program (RFC 9110, section 8.4.1.1): LZW with codes that grow from 9 to at
+/// most 16 bits.
+pub struct Encoder<W: Write> {
+    inner: W,
+    max_bits: u32,
+    table: HashMap<u32, u32>,
+    free_ent: u32,
+    n_bits: u32,
+    max_code: u32,
+    /// Code of the longest string matched so far.
+    ent: Option<u32>,
+    bits: u64,
+    bit_count: u32,
+    group_codes: u32,
+    out: Vec<u8>,
+}
+
+impl<W: Write> Encoder<W> {
+    /// `max_bits` is clamped to 9-16; 16 compresses best.
+    pub fn new(inner: W, max_bits: u32) -> Self {
+        let max_bits = max_bits.clamp(MIN_BITS, MAX_BITS);
+        Self {
+            inner,
+            max_bits,
+            table: HashMap::new(),
+            free_ent: FIRST,
+            n_bits: MIN_BITS,
+            max_code: max_code(MIN_BITS, max_bits),
+            ent: None,
+            bits: 0,
+            bit_count: 0,
+            group_codes: 0,
+            out: vec![MAGIC[0], MAGIC[1], BLOCK_MODE | max_bits as u8],
+        }
+    }
+
+    fn put_bits(&mut self, value: u64, count: u32) {
+        self.bits |= value << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    fn output(&mut self, code: u32, clear: bool) {
+        self.put_bits(code as u64, self.n_bits);
+        self.group_codes += 1;
+
+        if clear || self.free_ent > self.max_code {
+            for _ in 0..group_padding(self.group_codes, self.n_bits) / 8 {
+                self.put_bits(0, 8);
+            }
+            self.put_bits(0, group_padding(self.group_codes, self.n_bits) % 8);
+            self.group_codes = 0;
+
+            self.n_bits = if clear { MIN_BITS } else { self.n_bits + 1 };
+            self.max_code = max_code(self.n_bits, self.max_bits);
+        }
+    }
+
+    /// Writes the last code and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(ent) = self.ent.take() {
+            // The stream ends here, so the group needs no padding
+            self.put_bits(ent as u64, self.n_bits);
+        }
+        if self.bit_count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.inner.write_all(&self.out)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &c in buf {
+            let Some(ent) = self.ent else {
+                self.ent = Some(c as u32);
+                continue;
+            };
+
+            let key = ent << 8 | c as u32;
+            if let Some(&code) = self.table.get(&key) {
+                self.ent = Some(code);
+                continue;
+            }
+
+            self.output(ent, false);
+            self.ent = Some(c as u32);
+            if self.free_ent < 1 << self.max_bits {
+                self.table.insert(key, self.free_ent);
+                self.free_ent += 1;
+            } else {
+                // The table is full; start over, so codes fit the data that follows
+                self.table.clear();
+                self.free_ent = FIRST;
+                self.output(CLEAR, true);
+            }
+        }
+
+        if self.out.len() >= OUT_CHUNK {
+            self.inner.write_all(&self.out)?;
+            self.out.clear();
+        }
+        Ok(buf.len())
+    }
+
+    /// Passes on the complete bytes. The current string and the bits of a partial
+    /// byte can't be emitted before the end without corrupting the stream.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.out)?;
+        self.out.clear();
+        self.inner.flush()
+    }
+}
+
+/// Decompresses data written to it into `W`.
+pub struct Decoder<W: Write> {
+    inner: W,
+    header: Vec<u8>,
+    max_bits: u32,
+    block_mode: bool,
+    prefix: Vec<u16>,
+    suffix: Vec<u8>,
+    free_ent: u32,
+    n_bits: u32,
+    max_code: u32,
+    old_code: Option<u32>,
+    fin_char: u8,
+    bits: u64,
+    bit_count: u32,
+    group_codes: u32,
+    skip_bits: u32,
+    stack: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl<W: Write> Decoder<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            header: Vec::with_capacity(3),
+            max_bits: MAX_BITS,
+            block_mode: true,
+            prefix: vec![0; 1 << MAX_BITS],
+            suffix: vec![0; 1 << MAX_BITS],
+            free_ent: FIRST,
+            n_bits: MIN_BITS,
+            max_code: max_code(MIN_BITS, MAX_BITS),
+            old_code: None,
+            fin_char: 0,
+            bits: 0,
+            bit_count: 0,
+            group_codes: 0,
+            skip_bits: 0,
+            stack: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    fn parse_header(&mut self) -> io::Result<()> {
+        let flags = self.header[2];
+        self.max_bits = (flags & BITS_MASK) as u32;
+        self.block_mode = flags & BLOCK_MODE != 0;
+        if self.header[..2] != MAGIC || !(MIN_BITS..=MAX_BITS).contains(&self.max_bits) {
+            return Err(invalid("not compress data"));
+        }
+
+        self.free_ent = if self.block_mode { FIRST } else { CLEAR };
+        self.max_code = max_code(MIN_BITS, self.max_bits);
+        Ok(())
+    }
+
+    /// Starts a new group of codes `n_bits` wide, after skipping the rest of the current one.
+    fn start_group(&mut self, n_bits: u32) {
+        self.skip_bits = group_padding(self.group_codes, self.n_bits);
+        self.group_codes = 0;
+        self.n_bits = n_bits;
+        self.max_code = max_code(n_bits, self.max_bits);
+    }
+
+    fn decode_code(&mut self, code: u32) -> io::Result<()> {
+        self.group_codes += 1;
+
+        if self.block_mode && code == CLEAR {
+            // The next code is a literal, and its entry is never used
+            self.free_ent = FIRST - 1;
+            self.start_group(MIN_BITS);
+            return Ok(());
+        }
+
+        let Some(old_code) = self.old_code else {
+            if code > 0xff {
+                return Err(invalid("compress data doesn't start with a literal"));
+            }
+            self.fin_char = code as u8;
+            self.out.push(self.fin_char);
+            self.old_code = Some(code);
+            return Ok(());
+        };
+
+        self.stack.clear();
+        let mut c = code;
+        if c >= self.free_ent {
+            // The code being defined: the previous string and its first byte
+            if c > self.free_ent {
+                return Err(invalid("invalid code in compress data"));
+            }
+            self.stack.push(self.fin_char);
+            c = old_code;
+        }
+        while c > 0xff {
+            if self.stack.len() > 1 << MAX_BITS {
+                return Err(invalid("invalid code in compress data"));
+            }
+            self.stack.push(self.suffix[c as usize]);
+            c = self.prefix[c as usize] as u32;
+        }
+        self.fin_char = c as u8;
+        self.stack.push(self.fin_char);
+        self.out.extend(self.stack.iter().rev());
+
+        if self.free_ent < 1 << self.max_bits {
+            self.prefix[self.free_ent as usize] = old_code as u16;
+            self.suffix[self.free_ent as usize] = self.fin_char;
+            self.free_ent += 1;
+        }
+        self.old_code = Some(code);
+
+        if self.free_ent > self.max_code {
+            self.start_group(self.n_bits + 1);
+        }
+        Ok(())
+    }
+
+    /// Checks that the header was complete, and flushes the output.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        if self.header.len() < 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated compress data",
+            ));
+        }
+        self.flush()
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut input = buf;
+        if self.header.len() < 3 {
+            let n = input.len().min(3 - self.header.len());
+            self.header.extend_from_slice(&input[..n]);
+            input = &input[n..];
+            if self.header.len() < 3 {
+                return Ok(buf.len());
+            }
+            self.parse_header()?;
+        }
+
+        for &byte in input {
+            self.bits |= (byte as u64) << self.bit_count;
+            self.bit_count += 8;
+
+            loop {
+                if self.skip_bits > 0 {
+                    let n = self.skip_bits.min(self.bit_count);
+                    self.bits >>= n;
+                    self.bit_count -= n;
+                    self.skip_bits -= n;
+                }
+                if self.skip_bits > 0 || self.bit_count < self.n_bits {
+                    break;
+                }
+
+                let code = (self.bits & ((1 << self.n_bits) - 1)) as u32;
+                self.bits >>= self.n_bits;
+                self.bit_count -= self.n_bits;
+                self.decode_code(code)?;
+            }
+
+            if self.out.len() >= OUT_CHUNK {
+                self.inner.write_all(&self.out)?;
+                self.out.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.out)?;
+        self.out.clear();
+        self.inner.flush()
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(content: &[u8], max_bits: u32) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new(), max_bits);
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decode(encoded: &[u8], piece: usize) -> io::Result<Vec<u8>> {
+        let mut decoder = Decoder::new(Vec::new());
+        for p in encoded.chunks(piece) {
+            decoder.write_all(p)?;
+        }
+        decoder.try_finish()?;
+        Ok(std::mem::take(decoder.get_mut()))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Pseudo-random words fill the table, so codes grow and get cleared
+        let mut seed = 1u32;
+        let content: Vec<u8> = (0..60_000)
+            .flat_map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                format!("w{} ", seed >> 20).into_bytes()
+            })
+            .collect();
+
+        for max_bits in [9, 12, 16] {
+            let encoded = encode(&content, max_bits);
+            assert!(encoded.len() < content.len());
+            assert_eq!(decode(&encoded, 1000).unwrap(), content);
+        }
+
+        assert_eq!(decode(&encode(b"", 16), 1).unwrap(), b"");
+        assert_eq!(decode(&encode(b"aaaaaaa", 16), 1).unwrap(), b"aaaaaaa");
+    }
+
+    #[test]
+    fn test_decode() {
+        // `compress` output for "TOBEORNOTTOBEORTOBEORNOT\n"
+        let encoded = [
+            0x1f, 0x9d, 0x90, 0x54, 0x9e, 0x08, 0x29, 0xf2, 0x44, 0x8a, 0x93, 0x27, 0x54, 0x02,
+            0x0e, 0x2c, 0xa8, 0x90, 0xa0, 0x41, 0x84, 0x0a, 0x00,
+        ];
+        assert_eq!(decode(&encoded, 5).unwrap(), b"TOBEORNOTTOBEORTOBEORNOT\n");
+
+        assert!(decode(b"\x1f\x8b\x08", 1).is_err());
+        assert!(decode(b"\x1f", 1).is_err());
+        // Code 300 before it is defined
+        assert!(decode(&[0x1f, 0x9d, 0x90, 0x41, 0x58, 0x02], 1).is_err());
+    }
+}
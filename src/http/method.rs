@@ -1,9 +1,28 @@
-use strum::EnumString;
+use strum::{Display, EnumString};
 
-#[derive(EnumString, Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(EnumString, Display, Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Method {
     #[strum(serialize = "GET")]
     GET,
+    #[strum(serialize = "HEAD")]
+    HEAD,
     #[strum(serialize = "POST")]
     POST,
+    #[strum(serialize = "PUT")]
+    PUT,
+    #[strum(serialize = "DELETE")]
+    DELETE,
+    #[strum(serialize = "CONNECT")]
+    CONNECT,
+    #[strum(serialize = "OPTIONS")]
+    OPTIONS,
+    #[strum(serialize = "TRACE")]
+    TRACE,
+    #[strum(serialize = "PATCH")]
+    PATCH,
+
+    /// Any other method token; method names are case-sensitive.
+    #[strum(default)]
+    Extension(String),
 }
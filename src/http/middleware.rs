@@ -1,7 +1,10 @@
 use crate::http::Response;
 use crate::http::handler::HandlerFunc;
 use crate::http::request::RequestContext;
+use std::sync::Arc;
 pub mod compression;
+pub mod conditional;
+pub mod decompression;
 
 pub trait Middleware: Send + Sync {
     fn handle(&self, ctx: &mut RequestContext, next: Next) -> Response;
@@ -9,6 +12,8 @@ pub trait Middleware: Send + Sync {
 
 pub struct Next<'a> {
     pub(crate) middlewares: &'a [Box<dyn Middleware>],
+    /// Middleware that runs after `middlewares`, like that of nested routers.
+    pub(crate) layers: &'a [Arc<[Box<dyn Middleware>]>],
     pub(crate) handler: &'a HandlerFunc,
 }
 
@@ -17,9 +22,17 @@ impl<'a> Next<'a> {
         if let Some((first, rest)) = self.middlewares.split_first() {
             let next = Next {
                 middlewares: rest,
+                layers: self.layers,
                 handler: self.handler,
             };
             first.handle(ctx, next)
+        } else if let Some((layer, rest)) = self.layers.split_first() {
+            let next = Next {
+                middlewares: layer,
+                layers: rest,
+                handler: self.handler,
+            };
+            next.run(ctx)
         } else {
             (self.handler)(ctx)
         }
@@ -1,104 +1,812 @@
-use crate::http::encoding::Encoding::{Gzip, Identity};
-use crate::http::encoding::{Encoding, EncodingVal};
+use crate::http::Response;
+use crate::http::body::{Body, StreamFn};
+use crate::http::dictionary;
+use crate::http::dictionary::{Dictionary, DictionaryStore};
+use crate::http::encoding::Encoding::{Br, Compress, Dcb, Dcz, Deflate, Gzip, Identity, Zstd};
+use crate::http::encoding::{AcceptEncoding, Encoding};
+use crate::http::etag::EntityTag;
+use crate::http::header::HeaderMap;
+use crate::http::method::Method;
 use crate::http::middleware::{Middleware, Next};
 use crate::http::request::RequestContext;
-use crate::http::{Response, ok};
-use anyhow::Result;
+use crate::http::status::Status;
 use flate2::Compression;
 use flate2::write::GzEncoder;
-use once_cell::sync::Lazy;
-use regex::Regex;
 use std::collections::HashMap;
+use std::io;
 use std::io::Write;
+use std::sync::Arc;
 
-pub struct CompressionMw {}
+const MAX_COMPRESSED_FILE: u64 = 1024 * 1024;
+
+/// Types that are compressed already, so encoding them again only costs time.
+const DEFAULT_DENIED_TYPES: [&str; 16] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "audio/*",
+    "video/*",
+    "font/woff",
+    "font/woff2",
+    "application/zip",
+    "application/gzip",
+    "application/zstd",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/vnd.rar",
+];
+
+/// Codings the middleware can produce, in order of preference.
+const SUPPORTED: &[Encoding] = &[
+    #[cfg(feature = "br")]
+    Br,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    Gzip,
+    #[cfg(feature = "deflate")]
+    Deflate,
+    // Only for clients that know nothing better
+    #[cfg(feature = "compress")]
+    Compress,
+];
+
+/// When the encoder of a streamed body passes its output on to the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlushPolicy {
+    /// When the encoder's buffers are full and at the end, for the best compression.
+    Buffered,
+    /// Also whenever the producer flushes the body.
+    OnFlush,
+    /// After every write, for feeds where each event must go out at once.
+    EveryWrite,
+}
+
+/// Compresses response bodies with the best coding the client accepts.
+pub struct CompressionMw {
+    levels: HashMap<Encoding, u32>,
+    flush_policy: FlushPolicy,
+    min_size: u64,
+    allowed_types: Vec<String>,
+    denied_types: Vec<String>,
+    dictionaries: Option<Arc<DictionaryStore>>,
+}
+
+impl Default for CompressionMw {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompressionMw {
+    /// Brotli's and zstd's levels favor speed over size, as bodies are
+    /// compressed on every request. dcb is the exception, as brotli makes little
+    /// use of a dictionary below level 6.
+    pub fn new() -> Self {
+        Self {
+            levels: HashMap::from([
+                (Gzip, 6),
+                (Deflate, 6),
+                (Br, 4),
+                (Zstd, 3),
+                (Dcb, 6),
+                (Dcz, 3),
+                (Compress, 16),
+            ]),
+            flush_policy: FlushPolicy::OnFlush,
+            min_size: 0,
+            allowed_types: Vec::new(),
+            denied_types: DEFAULT_DENIED_TYPES.iter().map(|t| t.to_string()).collect(),
+            dictionaries: None,
+        }
+    }
+
+    /// Compression level of `encoding`: 0-9 for gzip and deflate, 0-11 for br
+    /// and 1-22 for zstd, and the same for dcb and dcz. For compress it is the
+    /// maximum code size, 9-16 bits. Out of range levels are clamped.
+    pub fn level(mut self, encoding: Encoding, level: u32) -> Self {
+        self.levels.insert(encoding, level);
+        self
+    }
+
+    /// Flushing of compressed streamed bodies, `OnFlush` by default.
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.flush_policy = policy;
+        self
+    }
+
+    /// Bodies smaller than this are sent as they are. 0, the default, compresses
+    /// everything.
+    pub fn min_size(mut self, size: u64) -> Self {
+        self.min_size = size;
+        self
+    }
+
+    /// Only compress these media types, like `text/*` or `application/json`.
+    /// Empty, the default, allows any type that isn't denied.
+    pub fn allowed_types(mut self, types: &[&str]) -> Self {
+        self.allowed_types = types.iter().map(|t| t.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Never compress these media types. Defaults to common compressed formats.
+    pub fn denied_types(mut self, types: &[&str]) -> Self {
+        self.denied_types = types.iter().map(|t| t.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Compresses with shared dictionaries (RFC 9842) when the client has one from
+    /// the store, and offers designated responses as dictionaries.
+    pub fn dictionaries(mut self, store: Arc<DictionaryStore>) -> Self {
+        self.dictionaries = Some(store);
+        self
+    }
+
+    /// The codings `resp` may be encoded with, in order of preference.
+    fn codings_for(&self, resp: &Response, dictionary: bool) -> Vec<Encoding> {
+        let mut supported = Vec::new();
+        if resp.content.is_empty()
+            || resp.headers.contains("Content-Encoding")
+            || !self.should_compress(resp)
+        {
+            return supported;
+        }
+        // Dictionaries are only used for bodies that are compressed whole
+        if dictionary && !matches!(resp.content, Body::Stream(_)) {
+            supported.extend_from_slice(dictionary::SUPPORTED);
+        }
+        supported.extend_from_slice(SUPPORTED);
+        supported
+    }
+
+    fn should_compress(&self, resp: &Response) -> bool {
+        let size = match &resp.content {
+            Body::Bytes(c) => Some(c.len() as u64),
+            Body::File(f) if f.len() <= MAX_COMPRESSED_FILE => Some(f.len()),
+            // The size of a stream isn't known in advance
+            Body::Stream(_) => None,
+            // Large files are sent as they are
+            _ => return false,
+        };
+        if !resp.compressible || size.is_some_and(|s| s < self.min_size) {
+            return false;
+        }
+
+        // Byte ranges refer to the uncompressed content
+        if resp.status.code_num == Status::PARTIAL_CONTENT.code_num {
+            return false;
+        }
+
+        let no_transform = resp
+            .headers
+            .get_all("Cache-Control")
+            .flat_map(|v| v.split(','))
+            .any(|d| d.trim().eq_ignore_ascii_case("no-transform"));
+        if no_transform {
+            return false;
+        }
+
+        let media_type = resp.headers.get("Content-Type").map(|t| {
+            t.split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        });
+        let matches = |patterns: &[String]| {
+            media_type
+                .as_deref()
+                .is_some_and(|t| patterns.iter().any(|p| media_type_matches(p, t)))
+        };
+        (self.allowed_types.is_empty() || matches(&self.allowed_types))
+            && !matches(&self.denied_types)
+    }
+
+    fn level_of(&self, encoding: Encoding) -> u32 {
+        self.levels.get(&encoding).copied().unwrap_or_default()
+    }
+
+    fn encode(&self, encoding: Encoding, content: &[u8]) -> io::Result<Vec<u8>> {
+        let out = Vec::with_capacity(content.len() / 2);
+        let mut encoder = StreamEncoder::new(encoding, self.level_of(encoding), out)?;
+        encoder.write_all(content)?;
+        encoder.finish()
+    }
+
+    fn encode_with(
+        &self,
+        encoding: Encoding,
+        dictionary: Option<&Dictionary>,
+        content: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        match dictionary {
+            Some(d) if dictionary::SUPPORTED.contains(&encoding) => {
+                d.encode(encoding, self.level_of(encoding), content)
+            }
+            _ => self.encode(encoding, content),
+        }
+    }
+
+    /// Compresses the body as the producer writes it.
+    fn encode_stream(&self, encoding: Encoding, f: StreamFn) -> Body {
+        let level = self.level_of(encoding);
+        let policy = self.flush_policy;
+
+        Body::stream(move |w| {
+            let mut encoder = FlushingEncoder {
+                encoder: StreamEncoder::new(encoding, level, w)?,
+                policy,
+            };
+            let trailers = f(&mut encoder)?;
+            encoder.encoder.finish()?;
+            Ok(trailers)
+        })
+    }
+}
+
+/// An encoder writing the compressed data into `W` as it goes.
+enum StreamEncoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    #[cfg(feature = "deflate")]
+    Deflate(flate2::write::ZlibEncoder<W>),
+    #[cfg(feature = "br")]
+    // The encoder state is large, and would make every variant that size
+    Br(Box<brotli::CompressorWriter<W>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    #[cfg(feature = "compress")]
+    Compress(crate::http::lzw::Encoder<W>),
+}
+
+impl<W: Write> StreamEncoder<W> {
+    /// Levels are clamped to the range of the coding.
+    fn new(encoding: Encoding, level: u32, w: W) -> io::Result<Self> {
+        match encoding {
+            Gzip => Ok(Self::Gzip(GzEncoder::new(
+                w,
+                Compression::new(level.min(9)),
+            ))),
+            // "deflate" in HTTP is the zlib format (RFC 9110, section 8.4.1.2)
+            #[cfg(feature = "deflate")]
+            Deflate => Ok(Self::Deflate(flate2::write::ZlibEncoder::new(
+                w,
+                Compression::new(level.min(9)),
+            ))),
+            #[cfg(feature = "br")]
+            Br => Ok(Self::Br(Box::new(brotli::CompressorWriter::new(
+                w,
+                4096,
+                level.min(11),
+                22,
+            )))),
+            #[cfg(feature = "zstd")]
+            Zstd => Ok(Self::Zstd(zstd::stream::write::Encoder::new(
+                w,
+                level.clamp(1, 22) as i32,
+            )?)),
+            #[cfg(feature = "compress")]
+            Compress => Ok(Self::Compress(crate::http::lzw::Encoder::new(w, level))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("can't encode {}", encoding),
+            )),
+        }
+    }
+
+    /// Writes the end of the compressed data and returns the inner writer.
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Gzip(e) => e.finish(),
+            #[cfg(feature = "deflate")]
+            Self::Deflate(e) => e.finish(),
+            #[cfg(feature = "br")]
+            Self::Br(e) => Ok(e.into_inner()),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(e) => e.finish(),
+            #[cfg(feature = "compress")]
+            Self::Compress(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for StreamEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Gzip(e) => e.write(buf),
+            #[cfg(feature = "deflate")]
+            Self::Deflate(e) => e.write(buf),
+            #[cfg(feature = "br")]
+            Self::Br(e) => e.write(buf),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(e) => e.write(buf),
+            #[cfg(feature = "compress")]
+            Self::Compress(e) => e.write(buf),
+        }
+    }
+
+    /// Emits everything written so far, so the client can decode it.
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Gzip(e) => e.flush(),
+            #[cfg(feature = "deflate")]
+            Self::Deflate(e) => e.flush(),
+            #[cfg(feature = "br")]
+            Self::Br(e) => e.flush(),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(e) => e.flush(),
+            #[cfg(feature = "compress")]
+            Self::Compress(e) => e.flush(),
+        }
+    }
+}
+
+struct FlushingEncoder<W: Write> {
+    encoder: StreamEncoder<W>,
+    policy: FlushPolicy,
+}
+
+impl<W: Write> Write for FlushingEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.encoder.write(buf)?;
+        if self.policy == FlushPolicy::EveryWrite {
+            self.encoder.flush()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.policy {
+            FlushPolicy::Buffered => Ok(()),
+            FlushPolicy::OnFlush | FlushPolicy::EveryWrite => self.encoder.flush(),
+        }
+    }
+}
 
 impl Middleware for CompressionMw {
     fn handle(&self, ctx: &mut RequestContext, next: Next) -> Response {
-        let encodings = Self::get_accepted_encodings(ctx);
-
-        match encodings {
-            Ok(encodings) => {
-                let resp_encoding = if encodings.contains_key(&Gzip) {
-                    Gzip
-                } else {
-                    Identity
-                };
+        // A malformed header is ignored, as if it had not been sent
+        let accepted = match ctx.get_header("Accept-Encoding").map(AcceptEncoding::parse) {
+            Some(Ok(accepted)) => Some(accepted),
+            Some(Err(e)) => {
+                println!("ignoring Accept-Encoding: {}", e);
+                None
+            }
+            None => None,
+        };
 
-                let mut resp = next.run(ctx);
-
-                if let Some(c) = &resp.content {
-                    if resp_encoding != Identity {
-                        resp.headers.insert(
-                            "Content-Encoding".to_string(),
-                            resp_encoding.to_string().to_lowercase(),
-                        );
-                        let mut encoder =
-                            GzEncoder::new(Vec::with_capacity(c.len()), Compression::default());
-                        encoder.write_all(c).unwrap();
-                        resp.content = Some(encoder.finish().unwrap());
-                    }
-                }
+        let path = ctx.request().path.clone();
+        let dictionary = self.dictionaries.as_ref().and_then(|store| {
+            ctx.get_header("Available-Dictionary")
+                .and_then(|h| store.find(h))
+                .filter(|d| d.matches(&path))
+        });
+
+        let mut resp = next.run(ctx);
+        add_vary(&mut resp.headers, "Accept-Encoding");
+        if let Some(store) = &self.dictionaries {
+            let method = &ctx.request().method;
+            offer_dictionary(store, &path, method, accepted.as_ref(), &mut resp);
+        }
 
-                resp
+        // Without the header any coding is acceptable, and identity is the safest choice
+        let Some(accepted) = accepted else {
+            return resp;
+        };
+        // A 304 has to carry the validator of the response it stands for, which
+        // may have been encoded. Without that response there is nothing to go by.
+        if resp.status.code_num == Status::NOT_MODIFIED.code_num {
+            let encoding = match resp.replaced.take() {
+                Some(replaced) if resp.compressible => {
+                    accepted.negotiate(&self.codings_for(&replaced, dictionary.is_some()))
+                }
+                _ => None,
+            };
+            if let Some(encoding) = encoding.filter(|e| *e != Identity) {
+                tag_coding(&mut resp.headers, encoding);
             }
-            Err(e) => {
-                println!("{}", e);
-                ok()
+            return resp;
+        }
+
+        // Encoded responses, like precompressed files, were negotiated by the handler
+        if resp.content.is_empty() || resp.headers.contains("Content-Encoding") {
+            return resp;
+        }
+
+        let supported = self.codings_for(&resp, dictionary.is_some());
+        let encoding = match accepted.negotiate(&supported) {
+            Some(encoding) => encoding,
+            // Error responses are sent as they are rather than hidden behind a 406
+            None if !(200..300).contains(&resp.status.code_num) => return resp,
+            None => {
+                return Response::from_parts(
+                    Status::NOT_ACCEPTABLE,
+                    HeaderMap::from([("Vary", "Accept-Encoding")]),
+                    None,
+                );
             }
+        };
+        if encoding == Identity {
+            return resp;
         }
+
+        resp.content = match std::mem::replace(&mut resp.content, Body::Empty) {
+            Body::Stream(f) => self.encode_stream(encoding, f),
+            content => {
+                let encoded = match &content {
+                    Body::Bytes(c) => self.encode_with(encoding, dictionary.as_deref(), c),
+                    // Small files are compressed in memory
+                    Body::File(f) => f
+                        .read_to_vec()
+                        .and_then(|c| self.encode_with(encoding, dictionary.as_deref(), &c)),
+                    Body::Empty | Body::Stream(_) => unreachable!(),
+                };
+                match encoded {
+                    Ok(encoded) => Body::Bytes(encoded),
+                    Err(e) => {
+                        println!("error compressing response: {}", e);
+                        resp.content = content;
+                        return resp;
+                    }
+                }
+            }
+        };
+
+        resp.headers
+            .insert("Content-Encoding", encoding.to_string());
+        resp.headers.remove("Content-Length");
+        tag_coding(&mut resp.headers, encoding);
+
+        resp
     }
 }
 
-static ACCEPT_ENCODING_RE: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"(?P<enc>[\w*\-]+)(?:;\s*q=(?P<q>0(\.\d+)?|1(\.0+)?))?").unwrap());
+/// The encoded bytes differ, so they get a tag of their own. It stays strong,
+/// for `If-Match` and `If-Range` to work with it.
+fn tag_coding(headers: &mut HeaderMap, encoding: Encoding) {
+    if let Some(etag) = headers.get("ETag").and_then(EntityTag::parse) {
+        headers.insert("ETag", etag.with_coding(encoding).to_string());
+    }
+}
 
-impl CompressionMw {
-    fn get_accepted_encodings(ctx: &mut RequestContext) -> Result<HashMap<Encoding, EncodingVal>> {
-        ctx.get_header("accept-encoding")
-            .map(Self::parse_accept_encoding)
-            .unwrap_or_else(|| {
-                Ok(HashMap::from([(
-                    Identity,
-                    EncodingVal {
-                        encoding: Identity,
-                        quality: 1.0,
-                    },
-                )]))
-            })
+/// Keeps `CompressionMw` from encoding the responses of the routes it is
+/// added to, like streams that should reach the client unbuffered.
+pub struct NoCompressionMw;
+
+impl Middleware for NoCompressionMw {
+    fn handle(&self, ctx: &mut RequestContext, next: Next) -> Response {
+        next.run(ctx).without_compression()
     }
+}
 
-    fn parse_accept_encoding(header: &str) -> anyhow::Result<HashMap<Encoding, EncodingVal>> {
-        ACCEPT_ENCODING_RE
-            .captures_iter(header)
-            .flat_map(|cap| {
-                let encoding = Encoding::try_from(cap.name("enc").unwrap().as_str());
+/// Adds a designated 200 response to the dictionary store and tells the client to
+/// keep it as a dictionary, and marks responses that can be compressed with a
+/// dictionary as varying on it. Only clients that accept a dictionary coding get
+/// the offer, and a response with a strong `ETag` is hashed once.
+fn offer_dictionary(
+    store: &DictionaryStore,
+    path: &str,
+    method: &Method,
+    accepted: Option<&AcceptEncoding>,
+    resp: &mut Response,
+) {
+    if store.covers(path) {
+        add_vary(&mut resp.headers, "Available-Dictionary");
+    }
+
+    let Some(match_pattern) = store.designation(path) else {
+        return;
+    };
+    if resp.status.code_num != Status::OK.code_num || resp.headers.contains("Content-Encoding") {
+        return;
+    }
+    // Of no use to a client without a dictionary coding, and a HEAD response
+    // has no content to keep
+    let usable = accepted.is_some_and(|a| {
+        dictionary::SUPPORTED
+            .iter()
+            .any(|&e| a.quality(e).is_some_and(|q| q > 0.0))
+    });
+    if *method == Method::HEAD || !usable {
+        return;
+    }
 
-                let quality = cap
-                    .name("q")
-                    .map(|m| m.as_str().parse::<f32>().unwrap())
-                    .unwrap_or(1.0);
+    let etag = resp
+        .headers
+        .get("ETag")
+        .filter(|e| EntityTag::parse(e).is_some_and(|e| !e.weak))
+        .map(str::to_string);
+    let dictionary = match etag
+        .as_deref()
+        .and_then(|e| store.offered(e, match_pattern))
+    {
+        Some(dictionary) => dictionary,
+        None => {
+            // The dictionary is the content as the client decodes it
+            let content = match &resp.content {
+                Body::Bytes(c) => c.clone(),
+                Body::File(f) if f.len() <= MAX_COMPRESSED_FILE => match f.read_to_vec() {
+                    Ok(c) => c,
+                    Err(_) => return,
+                },
+                _ => return,
+            };
+            let mut dictionary = Dictionary::new(content, match_pattern);
+            if let Some(etag) = etag {
+                dictionary = dictionary.etag(etag);
+            }
+            store.add(dictionary)
+        }
+    };
+    resp.headers
+        .insert("Use-As-Dictionary", dictionary.use_as_dictionary());
+}
 
-                encoding.map(|encoding| Ok((encoding, EncodingVal { encoding, quality })))
-            })
-            .collect()
+/// Matches a media type against `type/subtype`, `type/*` or `*/*`.
+fn media_type_matches(pattern: &str, media_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(prefix) => media_type.split_once('/').is_some_and(|(t, _)| t == prefix),
+        None => pattern == media_type,
+    }
+}
+
+/// Adds `field` to the `Vary` header, unless it is already listed.
+pub fn add_vary(headers: &mut HeaderMap, field: &str) {
+    let listed = headers
+        .get_all("Vary")
+        .flat_map(|v| v.split(','))
+        .any(|f| f.trim() == "*" || f.trim().eq_ignore_ascii_case(field));
+    if !listed {
+        headers.append("Vary", field);
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::http::handler::HandlerFunc;
+    use crate::http::request::{Request, with_context};
+    #[cfg(any(feature = "br", feature = "zstd"))]
+    use base64::{Engine, engine::general_purpose::STANDARD};
 
     #[test]
-    fn test_re() {
-        assert!(ACCEPT_ENCODING_RE.is_match("encoding-1"));
+    fn test_add_vary() {
+        let mut headers = HeaderMap::from([("Vary", "Origin, accept-encoding")]);
+        add_vary(&mut headers, "Accept-Encoding");
+        assert_eq!(headers.get_all("Vary").count(), 1);
+
+        add_vary(&mut headers, "Cookie");
+        assert_eq!(
+            headers.get_all("Vary").collect::<Vec<_>>(),
+            vec!["Origin, accept-encoding", "Cookie"]
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let mw = CompressionMw::new().level(Gzip, 9);
+        let content = b"hello hello hello hello".repeat(10);
+
+        let mut decoded = Vec::new();
+        let gzip = mw.encode(Gzip, &content).unwrap();
+        io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&gzip[..]), &mut decoded).unwrap();
+        assert_eq!(decoded, content);
+
+        #[cfg(feature = "deflate")]
+        {
+            let mut decoded = Vec::new();
+            let deflate = mw.encode(Deflate, &content).unwrap();
+            io::Read::read_to_end(
+                &mut flate2::read::ZlibDecoder::new(&deflate[..]),
+                &mut decoded,
+            )
+            .unwrap();
+            assert_eq!(decoded, content);
+        }
+
+        #[cfg(feature = "br")]
+        {
+            let mut decoded = Vec::new();
+            let br = mw.encode(Br, &content).unwrap();
+            io::Read::read_to_end(&mut brotli::Decompressor::new(&br[..], 4096), &mut decoded)
+                .unwrap();
+            assert_eq!(decoded, content);
+        }
+
+        #[cfg(feature = "zstd")]
+        {
+            let zstd = mw.encode(Zstd, &content).unwrap();
+            assert_eq!(zstd::decode_all(&zstd[..]).unwrap(), content);
+        }
+
+        #[cfg(feature = "compress")]
+        {
+            let compress = mw.encode(Compress, &content).unwrap();
+            let mut decoder = crate::http::lzw::Decoder::new(Vec::new());
+            decoder.write_all(&compress).unwrap();
+            decoder.try_finish().unwrap();
+            assert_eq!(decoder.get_mut(), &content);
+        }
+
+        assert!(mw.encode(Dcb, &content).is_err());
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let resp = |content_type: &str, content: &[u8]| {
+            Response::from_parts(
+                Status::OK,
+                HeaderMap::from([("Content-Type", content_type)]),
+                content.to_vec(),
+            )
+        };
+        let mw = CompressionMw::new().min_size(4);
+
+        assert!(mw.should_compress(&resp("text/plain; charset=utf-8", b"abcd")));
+        assert!(!mw.should_compress(&resp("text/plain", b"abc")));
+        assert!(!mw.should_compress(&resp("image/png", b"abcd")));
+        assert!(!mw.should_compress(&resp("video/mp4", b"abcd")));
+        assert!(!mw.should_compress(&resp("text/plain", b"abcd").without_compression()));
+
+        let mut no_transform = resp("text/plain", b"abcd");
+        no_transform
+            .headers
+            .insert("Cache-Control", "max-age=60, No-Transform");
+        assert!(!mw.should_compress(&no_transform));
+
+        let mw = mw.allowed_types(&["text/*", "application/json"]);
+        assert!(mw.should_compress(&resp("application/json", b"abcd")));
+        assert!(!mw.should_compress(&resp("application/octet-stream", b"abcd")));
+    }
+
+    #[test]
+    fn test_encode_stream() {
+        /// Records how much output had arrived at each flush.
+        #[derive(Default)]
+        struct Recorder {
+            out: Vec<u8>,
+            flushes: Vec<usize>,
+        }
+
+        impl Write for Recorder {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.out.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.flushes.push(self.out.len());
+                Ok(())
+            }
+        }
+
+        let run = |policy: FlushPolicy| {
+            let body = Body::from_chunks([b"event: 1\n\n".to_vec(), b"event: 2\n\n".to_vec()]);
+            let Body::Stream(f) = body else {
+                unreachable!()
+            };
+            let mw = CompressionMw::new().flush_policy(policy);
+            let Body::Stream(encoded) = mw.encode_stream(Gzip, f) else {
+                unreachable!()
+            };
+
+            let mut recorder = Recorder::default();
+            encoded(&mut recorder).unwrap();
+
+            let mut decoded = String::new();
+            io::Read::read_to_string(
+                &mut flate2::read::GzDecoder::new(&recorder.out[..]),
+                &mut decoded,
+            )
+            .unwrap();
+            assert_eq!(decoded, "event: 1\n\nevent: 2\n\n");
+            recorder.flushes
+        };
+
+        // The first event is out, gzip header included, before the second is written
+        let flushes = run(FlushPolicy::OnFlush);
+        assert_eq!(flushes.len(), 2);
+        assert!(flushes[0] > 10);
+
+        assert!(run(FlushPolicy::Buffered).is_empty());
+    }
+
+    #[test]
+    fn test_not_modified_etag() {
+        let run = |mw: &CompressionMw, accept: &str, handler: HandlerFunc| {
+            let request = Request::for_test(Method::GET, "/", &[("Accept-Encoding", accept)]);
+            let next = Next {
+                middlewares: &[],
+                layers: &[],
+                handler: &handler,
+            };
+            let resp = with_context(&request, |ctx| mw.handle(ctx, next));
+            resp.headers.get("ETag").map(str::to_string)
+        };
+        // A 304 in place of a 200 with `content_type` and `size` bytes
+        let not_modified = |content_type: &'static str, size: usize| -> HandlerFunc {
+            Box::new(move |_| {
+                let headers = HeaderMap::from([("ETag", "\"x\"")]);
+                let replaced = Response::from_parts(
+                    Status::OK,
+                    HeaderMap::from([("Content-Type", content_type), ("ETag", "\"x\"")]),
+                    vec![b'a'; size],
+                );
+                Response::from_parts(Status::NOT_MODIFIED, headers, None).replacing(replaced)
+            })
+        };
+        let mw = CompressionMw::new();
+
+        // Like the 200 would be
+        let etag = run(&mw, "gzip", not_modified("text/plain", 100));
+        assert_eq!(etag.as_deref(), Some("\"x-gzip\""));
+        let etag = run(&mw, "identity", not_modified("text/plain", 100));
+        assert_eq!(etag.as_deref(), Some("\"x\""));
+        let etag = run(&mw, "gzip", not_modified("image/png", 100));
+        assert_eq!(etag.as_deref(), Some("\"x\""));
+        let etag = run(
+            &CompressionMw::new().min_size(1000),
+            "gzip",
+            not_modified("text/plain", 100),
+        );
+        assert_eq!(etag.as_deref(), Some("\"x\""));
+        let handler = not_modified("text/plain", 100);
+        let etag = run(
+            &mw,
+            "gzip",
+            Box::new(move |ctx| handler(ctx).without_compression()),
+        );
+        assert_eq!(etag.as_deref(), Some("\"x\""));
+
+        // Without the response it stands for, the tag is left as the handler set it
+        let handler: HandlerFunc = Box::new(|_| {
+            let headers = HeaderMap::from([("ETag", "\"x\"")]);
+            Response::from_parts(Status::NOT_MODIFIED, headers, None)
+        });
+        assert_eq!(run(&mw, "gzip", handler).as_deref(), Some("\"x\""));
+
+        // The 200 and the 304 agree
+        let handler: HandlerFunc = Box::new(|_| {
+            let headers = HeaderMap::from([("Content-Type", "text/plain"), ("ETag", "\"x\"")]);
+            Response::from_parts(Status::OK, headers, vec![b'a'; 100])
+        });
+        assert_eq!(run(&mw, "gzip", handler).as_deref(), Some("\"x-gzip\""));
+    }
+    #[test]
+    #[cfg(any(feature = "br", feature = "zstd"))]
+    fn test_offer_dictionary() {
+        let store = DictionaryStore::new().designate("/app.*.js", "/app.*.js");
+        let offer = |method: Method, accept: Option<&str>, etag: &str, content: &[u8]| {
+            let accepted = accept.map(|a| AcceptEncoding::parse(a).unwrap());
+            let headers = HeaderMap::from([("ETag", etag)]);
+            let mut resp = Response::from_parts(Status::OK, headers, content.to_vec());
+            offer_dictionary(&store, "/app.1.js", &method, accepted.as_ref(), &mut resp);
+            assert!(resp.headers.get("Vary").is_some());
+            resp.headers.get("Use-As-Dictionary").map(str::to_string)
+        };
+        let available = |content: &[u8]| {
+            let hash = Dictionary::new(content.to_vec(), "/").hash().to_owned();
+            format!(":{}:", STANDARD.encode(hash))
+        };
+
+        // Only to clients that can use it
+        assert_eq!(offer(Method::GET, None, "\"1\"", b"v1"), None);
+        assert_eq!(
+            offer(Method::GET, Some("gzip, dcb;q=0"), "\"1\"", b"v1"),
+            None
+        );
+        assert_eq!(offer(Method::HEAD, Some("dcb, dcz"), "\"1\"", b"v1"), None);
+        assert!(store.find(&available(b"v1")).is_none());
 
-        let cc: Vec<&str> = ACCEPT_ENCODING_RE
-            .captures_iter("encoding-1, gzip")
-            .filter_map(|c| c.name("enc"))
-            .map(|m| m.as_str())
-            .collect();
+        let offered = offer(Method::GET, Some("dcb, dcz"), "\"1\"", b"v1");
+        assert_eq!(offered.as_deref(), Some("match=\"/app.*.js\""));
+        assert!(store.find(&available(b"v1")).is_some());
 
-        assert_eq!(cc, vec!["encoding-1", "gzip"])
+        // The same strong tag stands for the same content, which isn't hashed again
+        assert!(offer(Method::GET, Some("dcb, dcz"), "\"1\"", b"v2").is_some());
+        assert!(store.find(&available(b"v2")).is_none());
+        assert!(offer(Method::GET, Some("dcb, dcz"), "W/\"1\"", b"v2").is_some());
+        assert!(store.find(&available(b"v2")).is_some());
     }
 }
@@ -0,0 +1,343 @@
+use crate::http::Response;
+use crate::http::body::Body;
+use crate::http::etag::{EntityTag, list_contains};
+use crate::http::header::HeaderMap;
+use crate::http::method::Method;
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::RequestContext;
+use crate::http::status::Status;
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Headers of a 200 response that are repeated in a 304 (RFC 9110, section 15.4.5).
+const NOT_MODIFIED_HEADERS: [&str; 7] = [
+    "Cache-Control",
+    "Content-Location",
+    "Date",
+    "ETag",
+    "Expires",
+    "Last-Modified",
+    "Vary",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Precondition {
+    Passed,
+    NotModified,
+    Failed,
+}
+
+/// Validators of the current state of a resource.
+#[derive(Debug, Default, Clone)]
+pub struct Validators {
+    pub etag: Option<EntityTag>,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Strong ETag from the modification time and size of a file.
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let last_modified = metadata.modified().ok();
+        let etag = last_modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| EntityTag::strong(format!("{:x}-{:x}", d.as_nanos(), metadata.len())));
+        Self {
+            etag,
+            last_modified,
+        }
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Self {
+            etag: headers.get("ETag").and_then(EntityTag::parse),
+            last_modified: headers
+                .get("Last-Modified")
+                .and_then(|d| httpdate::parse_http_date(d).ok()),
+        }
+    }
+}
+
+/// Evaluates the preconditions of a request in the order of RFC 9110, section 13.2.2.
+/// `current` is `None` when the target resource has no current representation.
+///
+/// `Range` and `If-Range` are left to the handler.
+pub fn evaluate_preconditions(ctx: &RequestContext, current: Option<&Validators>) -> Precondition {
+    let etag = current.and_then(|v| v.etag.as_ref());
+    let last_modified = current.and_then(|v| v.last_modified);
+    let method = &ctx.request().method;
+    let is_read = *method == Method::GET || *method == Method::HEAD;
+
+    if let Some(if_match) = ctx.get_header("If-Match") {
+        let matched = if if_match.trim() == "*" {
+            current.is_some()
+        } else {
+            etag.is_some_and(|t| list_contains(if_match, t, false))
+        };
+        if !matched {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = ctx.get_header("If-Unmodified-Since") {
+        if let (Ok(since), Some(modified)) = (httpdate::parse_http_date(since), last_modified) {
+            if !not_modified_since(modified, since) {
+                return Precondition::Failed;
+            }
+        }
+    }
+
+    if let Some(if_none_match) = ctx.get_header("If-None-Match") {
+        let matched = if if_none_match.trim() == "*" {
+            current.is_some()
+        } else {
+            etag.is_some_and(|t| list_contains(if_none_match, t, true))
+        };
+        if matched {
+            return if is_read {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if let Some(since) = ctx.get_header("If-Modified-Since") {
+        if let (true, Ok(since), Some(modified)) =
+            (is_read, httpdate::parse_http_date(since), last_modified)
+        {
+            if not_modified_since(modified, since) {
+                return Precondition::NotModified;
+            }
+        }
+    }
+
+    Precondition::Passed
+}
+
+/// The response for a precondition that didn't pass; `headers` are those of
+/// the response that would have been sent otherwise.
+pub fn precondition_response(precondition: Precondition, headers: &HeaderMap) -> Option<Response> {
+    match precondition {
+        Precondition::Passed => None,
+        Precondition::NotModified => {
+            let headers = headers
+                .iter()
+                .filter(|(k, _)| {
+                    NOT_MODIFIED_HEADERS
+                        .iter()
+                        .any(|h| h.eq_ignore_ascii_case(k))
+                })
+                .collect();
+            Some(Response::from_parts(Status::NOT_MODIFIED, headers, None))
+        }
+        Precondition::Failed => Some(Response::from_parts(
+            Status::PRECONDITION_FAILED,
+            HeaderMap::new(),
+            None,
+        )),
+    }
+}
+
+/// HTTP dates have a resolution of one second.
+fn not_modified_since(modified: SystemTime, since: SystemTime) -> bool {
+    let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    secs(modified) <= secs(since)
+}
+
+/// Answers conditional GET and HEAD requests with 304 or 412, using the `ETag`
+/// and `Last-Modified` of the handler's response. Responses with a buffered
+/// body and no `ETag` get one from a hash of the content.
+///
+/// Other methods change state, so their handlers have to check preconditions
+/// with `evaluate_preconditions` before acting.
+#[derive(Default)]
+pub struct ConditionalMw {
+    weak_etags: bool,
+}
+
+impl ConditionalMw {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generate weak ETags, for content that is equivalent but not byte-for-byte stable.
+    pub fn weak_etags(mut self, weak: bool) -> Self {
+        self.weak_etags = weak;
+        self
+    }
+}
+
+impl Middleware for ConditionalMw {
+    fn handle(&self, ctx: &mut RequestContext, next: Next) -> Response {
+        let mut resp = next.run(ctx);
+
+        let method = &ctx.request().method;
+        if (*method != Method::GET && *method != Method::HEAD)
+            || !(200..300).contains(&resp.status.code_num)
+        {
+            return resp;
+        }
+
+        if resp.status.code_num == Status::OK.code_num && !resp.headers.contains("ETag") {
+            if let Body::Bytes(content) = &resp.content {
+                let mut etag = EntityTag::from_content(content);
+                etag.weak = self.weak_etags;
+                resp.headers.insert("ETag", etag.to_string());
+            }
+        }
+
+        let current = Validators::from_headers(&resp.headers);
+        let precondition = evaluate_preconditions(ctx, Some(&current));
+        match precondition_response(precondition, &resp.headers) {
+            Some(not_modified) if not_modified.status.code_num == Status::NOT_MODIFIED.code_num => {
+                not_modified.replacing(resp)
+            }
+            Some(other) => other,
+            None => resp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::handler::HandlerFunc;
+    use crate::http::request::{Request, with_context};
+    use std::time::Duration;
+
+    #[test]
+    fn test_evaluate_preconditions() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let before = httpdate::fmt_http_date(modified - Duration::from_secs(60));
+        let after = httpdate::fmt_http_date(modified + Duration::from_secs(60));
+        let current = Validators {
+            etag: Some(EntityTag::strong("1")),
+            last_modified: Some(modified),
+        };
+
+        use Method::{GET, HEAD, POST, PUT};
+        use Precondition::{Failed, NotModified, Passed};
+        // Method, request headers, whether the resource exists, result
+        type Case<'a> = (Method, &'a [(&'a str, &'a str)], bool, Precondition);
+        let cases: &[Case] = &[
+            (GET, &[], true, Passed),
+            (GET, &[("If-None-Match", "\"1\"")], true, NotModified),
+            (
+                HEAD,
+                &[("If-None-Match", "\"0\", W/\"1\"")],
+                true,
+                NotModified,
+            ),
+            (GET, &[("If-None-Match", "\"2\"")], true, Passed),
+            (GET, &[("If-None-Match", "*")], false, Passed),
+            // Other methods fail instead of getting a 304
+            (PUT, &[("If-None-Match", "\"1\"")], true, Failed),
+            (PUT, &[("If-None-Match", "*")], true, Failed),
+            (PUT, &[("If-None-Match", "*")], false, Passed),
+            // If-Match needs a current representation and a strong match
+            (PUT, &[("If-Match", "*")], true, Passed),
+            (PUT, &[("If-Match", "*")], false, Failed),
+            (PUT, &[("If-Match", "\"1\"")], true, Passed),
+            (PUT, &[("If-Match", "W/\"1\"")], true, Failed),
+            // Tags of encoded responses stand for the resource too
+            (PUT, &[("If-Match", "\"1-gzip\"")], true, Passed),
+            (GET, &[("If-None-Match", "\"1-br\"")], true, NotModified),
+            (PUT, &[("If-Match", "\"1\"")], false, Failed),
+            // If-Match is evaluated first
+            (
+                GET,
+                &[("If-Match", "\"2\""), ("If-None-Match", "\"1\"")],
+                true,
+                Failed,
+            ),
+            (PUT, &[("If-Unmodified-Since", &before)], true, Failed),
+            (PUT, &[("If-Unmodified-Since", &after)], true, Passed),
+            // If-Unmodified-Since is ignored with If-Match
+            (
+                PUT,
+                &[("If-Match", "\"1\""), ("If-Unmodified-Since", &before)],
+                true,
+                Passed,
+            ),
+            (GET, &[("If-Modified-Since", &after)], true, NotModified),
+            (GET, &[("If-Modified-Since", &before)], true, Passed),
+            (GET, &[("If-Modified-Since", "yesterday")], true, Passed),
+            (POST, &[("If-Modified-Since", &after)], true, Passed),
+            // If-Modified-Since is ignored with If-None-Match
+            (
+                GET,
+                &[("If-None-Match", "\"2\""), ("If-Modified-Since", &after)],
+                true,
+                Passed,
+            ),
+        ];
+
+        for (method, headers, exists, expected) in cases {
+            let request = Request::for_test(method.clone(), "/", headers);
+            let current = exists.then_some(&current);
+            let result = with_context(&request, |ctx| evaluate_preconditions(ctx, current));
+            assert_eq!(result, *expected, "{} {:?}", method, headers);
+        }
+    }
+
+    #[test]
+    fn test_conditional_mw() {
+        let run = |mw: &ConditionalMw, method: Method, headers: &[(&str, &str)], status: Status| {
+            let handler: HandlerFunc = Box::new(move |_| {
+                let headers = HeaderMap::from([
+                    ("Content-Type", "text/plain"),
+                    ("Cache-Control", "max-age=60"),
+                ]);
+                Response::from_parts(
+                    Status {
+                        code_num: status.code_num,
+                        message: status.message,
+                    },
+                    headers,
+                    Some(b"hello".to_vec()),
+                )
+            });
+            let next = Next {
+                middlewares: &[],
+                layers: &[],
+                handler: &handler,
+            };
+            let request = Request::for_test(method, "/", headers);
+            with_context(&request, |ctx| mw.handle(ctx, next))
+        };
+
+        let mw = ConditionalMw::new();
+        let resp = run(&mw, Method::GET, &[], Status::OK);
+        let etag = resp.headers.get("ETag").unwrap().to_string();
+        assert!(!etag.starts_with("W/"));
+
+        let resp = run(&mw, Method::GET, &[("If-None-Match", &etag)], Status::OK);
+        assert_eq!(resp.status.code_num, 304);
+        assert_eq!(resp.headers.get("ETag"), Some(etag.as_str()));
+        assert_eq!(resp.headers.get("Cache-Control"), Some("max-age=60"));
+        assert_eq!(resp.headers.get("Content-Type"), None);
+        // For the compression middleware to tag it like the 200
+        assert!(resp.replaced.is_some_and(|r| r.status.code_num == 200));
+
+        let resp = run(&mw, Method::HEAD, &[("If-Match", "\"other\"")], Status::OK);
+        assert_eq!(resp.status.code_num, 412);
+
+        // Left to the handlers of other methods, and not applied to errors
+        let resp = run(&mw, Method::POST, &[("If-None-Match", "*")], Status::OK);
+        assert_eq!(resp.status.code_num, 200);
+        let resp = run(
+            &mw,
+            Method::GET,
+            &[("If-None-Match", "*")],
+            Status::NOT_FOUND,
+        );
+        assert_eq!(resp.status.code_num, 404);
+
+        let resp = run(
+            &ConditionalMw::new().weak_etags(true),
+            Method::GET,
+            &[],
+            Status::OK,
+        );
+        assert_eq!(
+            resp.headers.get("ETag"),
+            Some(format!("W/{}", etag).as_str())
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use crate::http::Response;
+use crate::http::encoding::Encoding;
+use crate::http::encoding::Encoding::Gzip;
+use crate::http::header::HeaderMap;
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::{BodyDecoder, RequestContext};
+use crate::http::status::Status;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+use thiserror::Error;
+
+const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Codings the middleware can decode.
+const SUPPORTED: &[Encoding] = &[
+    Gzip,
+    #[cfg(feature = "deflate")]
+    Encoding::Deflate,
+    #[cfg(feature = "br")]
+    Encoding::Br,
+    #[cfg(feature = "zstd")]
+    Encoding::Zstd,
+    #[cfg(feature = "compress")]
+    Encoding::Compress,
+];
+
+/// The error, wrapped in an `io::Error`, when a body decodes to more than the limit.
+#[derive(Error, Debug)]
+#[error("decompressed body exceeds {0} bytes")]
+pub struct BodyTooLarge(pub u64);
+
+impl BodyTooLarge {
+    pub fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<BodyTooLarge>())
+    }
+}
+
+/// Decodes request bodies sent with a `Content-Encoding`, so `RequestContext::body`
+/// returns the original content. `Request::content` stays as it was received.
+///
+/// Unsupported codings are answered with 415. Reading a body that decodes to more
+/// than the size limit fails with a `BodyTooLarge` error.
+pub struct DecompressionMw {
+    max_size: u64,
+}
+
+impl Default for DecompressionMw {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecompressionMw {
+    pub fn new() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+
+    /// Limit on the decoded size of a body, 64 MiB by default.
+    pub fn max_size(mut self, size: u64) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    fn unsupported() -> Response {
+        // Tells the client which codings would work (RFC 9110, section 12.5.3)
+        let accepted = SUPPORTED
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        Response::from_parts(
+            Status::UNSUPPORTED_MEDIA_TYPE,
+            HeaderMap::from([("Accept-Encoding", accepted)]),
+            None,
+        )
+    }
+}
+
+impl Middleware for DecompressionMw {
+    fn handle(&self, ctx: &mut RequestContext, next: Next) -> Response {
+        let Some(header) = ctx.get_header("Content-Encoding") else {
+            return next.run(ctx);
+        };
+
+        let codings: Vec<&str> = header
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty() && !c.eq_ignore_ascii_case("identity"))
+            .collect();
+
+        let decoder = match codings.as_slice() {
+            [] => return next.run(ctx),
+            [coding] => Encoding::from_str(&coding.to_ascii_lowercase())
+                .ok()
+                .filter(|e| SUPPORTED.contains(e))
+                .and_then(|e| WriteDecoder::new(e, self.max_size).ok()),
+            // Several codings applied on top of each other aren't supported
+            _ => None,
+        };
+
+        match decoder {
+            Some(decoder) => {
+                ctx.set_body_decoder(Box::new(decoder));
+                next.run(ctx)
+            }
+            None => Self::unsupported(),
+        }
+    }
+}
+
+/// Collects decoded output, failing once the total goes over the limit.
+struct LimitedBuf {
+    buf: Vec<u8>,
+    total: u64,
+    limit: u64,
+}
+
+impl Write for LimitedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.total += buf.len() as u64;
+        if self.total > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                BodyTooLarge(self.limit),
+            ));
+        }
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A decoder that is fed the raw body and writes the decoded data into a `LimitedBuf`.
+///
+/// Large decoder states are boxed, as they would make every variant that size.
+enum WriteDecoder {
+    Gzip(Box<flate2::write::GzDecoder<LimitedBuf>>),
+    #[cfg(feature = "deflate")]
+    Deflate(Box<flate2::write::ZlibDecoder<LimitedBuf>>),
+    #[cfg(feature = "br")]
+    Br(Box<brotli::DecompressorWriter<LimitedBuf>>),
+    // The writer behind `write::Decoder`, which can tell whether the frame ended
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::zio::Writer<LimitedBuf, zstd::stream::raw::Decoder<'static>>),
+    #[cfg(feature = "compress")]
+    Compress(crate::http::lzw::Decoder<LimitedBuf>),
+}
+
+impl WriteDecoder {
+    fn new(encoding: Encoding, limit: u64) -> io::Result<Self> {
+        let out = LimitedBuf {
+            buf: Vec::new(),
+            total: 0,
+            limit,
+        };
+
+        match encoding {
+            Gzip => Ok(Self::Gzip(Box::new(flate2::write::GzDecoder::new(out)))),
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => Ok(Self::Deflate(Box::new(flate2::write::ZlibDecoder::new(
+                out,
+            )))),
+            #[cfg(feature = "br")]
+            Encoding::Br => Ok(Self::Br(Box::new(brotli::DecompressorWriter::new(
+                out, 4096,
+            )))),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Ok(Self::Zstd(zstd::stream::zio::Writer::new(
+                out,
+                zstd::stream::raw::Decoder::new()?,
+            ))),
+            #[cfg(feature = "compress")]
+            Encoding::Compress => Ok(Self::Compress(crate::http::lzw::Decoder::new(out))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("can't decode {}", encoding),
+            )),
+        }
+    }
+
+    fn output(&mut self) -> &mut LimitedBuf {
+        match self {
+            Self::Gzip(d) => d.get_mut(),
+            #[cfg(feature = "deflate")]
+            Self::Deflate(d) => d.get_mut(),
+            #[cfg(feature = "br")]
+            Self::Br(d) => d.get_mut(),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(d) => d.writer_mut(),
+            #[cfg(feature = "compress")]
+            Self::Compress(d) => d.get_mut(),
+        }
+    }
+}
+
+impl BodyDecoder for WriteDecoder {
+    fn decode(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        // The end of the body, which fails if the compressed data is incomplete
+        if input.is_empty() {
+            match self {
+                Self::Gzip(d) => d.try_finish()?,
+                #[cfg(feature = "deflate")]
+                Self::Deflate(d) => d.try_finish()?,
+                #[cfg(feature = "br")]
+                Self::Br(d) => d.close()?,
+                #[cfg(feature = "zstd")]
+                Self::Zstd(d) => d.finish()?,
+                #[cfg(feature = "compress")]
+                Self::Compress(d) => d.try_finish()?,
+            }
+        } else {
+            match self {
+                Self::Gzip(d) => d.write_all(input)?,
+                #[cfg(feature = "deflate")]
+                Self::Deflate(d) => d.write_all(input)?,
+                #[cfg(feature = "br")]
+                Self::Br(d) => d.write_all(input)?,
+                #[cfg(feature = "zstd")]
+                Self::Zstd(d) => d.write_all(input)?,
+                #[cfg(feature = "compress")]
+                Self::Compress(d) => d.write_all(input)?,
+            }
+        }
+        Ok(std::mem::take(&mut self.output().buf))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        let content = b"hello hello hello".repeat(100);
+        let encoded = gzip(&content);
+
+        let mut decoder = WriteDecoder::new(Gzip, 10_000).unwrap();
+        let mut decoded = Vec::new();
+        for piece in encoded.chunks(7) {
+            decoded.extend(decoder.decode(piece).unwrap());
+        }
+        decoded.extend(decoder.decode(&[]).unwrap());
+        assert_eq!(decoded, content);
+    }
+
+    #[test]
+    fn test_decode_limit() {
+        let encoded = gzip(&vec![0; 100_000]);
+
+        let mut decoder = WriteDecoder::new(Gzip, 10_000).unwrap();
+        let e = encoded
+            .chunks(64)
+            .map(|piece| decoder.decode(piece))
+            .find_map(Result::err)
+            .unwrap();
+        assert!(BodyTooLarge::is(&e));
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let content = b"hello hello hello".repeat(100);
+        let encoded = [
+            (Gzip, gzip(&content)),
+            #[cfg(feature = "br")]
+            (Encoding::Br, {
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                encoder.write_all(&content).unwrap();
+                encoder.into_inner()
+            }),
+            #[cfg(feature = "zstd")]
+            (Encoding::Zstd, zstd::encode_all(&content[..], 3).unwrap()),
+        ];
+
+        for (encoding, encoded) in encoded {
+            let mut decoder = WriteDecoder::new(encoding, 10_000).unwrap();
+            decoder.decode(&encoded[..encoded.len() - 4]).unwrap();
+            assert!(decoder.decode(&[]).is_err(), "{}", encoding);
+
+            let mut decoder = WriteDecoder::new(encoding, 10_000).unwrap();
+            let mut decoded = decoder.decode(&encoded).unwrap();
+            decoded.extend(decoder.decode(&[]).unwrap());
+            assert_eq!(decoded, content, "{}", encoding);
+        }
+    }
+}
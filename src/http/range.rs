@@ -0,0 +1,176 @@
+use std::ops::RangeInclusive;
+
+/// More ranges than this in one request are treated as abuse and ignored.
+const MAX_RANGES: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// Serve the whole representation: no usable `Range` header.
+    Full,
+    /// Satisfiable byte ranges, in the order requested unless some overlapped.
+    Partial(Vec<RangeInclusive<u64>>),
+    /// The header was valid, but none of the ranges overlap the content.
+    Unsatisfiable,
+}
+
+/// Evaluates a `Range` header (RFC 9110, section 14.2) against content of `len` bytes.
+/// Invalid headers and units other than `bytes` are ignored, as the RFC allows.
+pub fn parse_range(header: &str, len: u64) -> RangeRequest {
+    let Some((unit, ranges)) = header.split_once('=') else {
+        return RangeRequest::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return RangeRequest::Full;
+    }
+
+    let mut satisfiable = Vec::new();
+    let mut count = 0;
+    for spec in ranges.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        count += 1;
+        if count > MAX_RANGES {
+            return RangeRequest::Full;
+        }
+
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let (first, last) = (first.trim(), last.trim());
+
+        let range = if first.is_empty() {
+            // suffix-range: the last N bytes
+            let Ok(suffix) = last.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            if suffix == 0 || len == 0 {
+                continue;
+            }
+            len.saturating_sub(suffix)..=len - 1
+        } else {
+            let Ok(first) = first.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            let last = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(l) if l >= first => l,
+                    _ => return RangeRequest::Full,
+                }
+            };
+            if first >= len {
+                continue;
+            }
+            first..=last.min(len - 1)
+        };
+        satisfiable.push(range);
+    }
+
+    if count == 0 {
+        RangeRequest::Full
+    } else if satisfiable.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(coalesce(satisfiable))
+    }
+}
+
+/// Merges overlapping ranges, so a request can't ask for the same bytes many
+/// times over (RFC 9110, section 14.2). The result is in ascending order if
+/// anything was merged.
+fn coalesce(mut ranges: Vec<RangeInclusive<u64>>) -> Vec<RangeInclusive<u64>> {
+    let overlap = |a: &RangeInclusive<u64>, b: &RangeInclusive<u64>| {
+        a.start() <= b.end() && b.start() <= a.end()
+    };
+    let any_overlap = ranges
+        .iter()
+        .enumerate()
+        .any(|(i, a)| ranges[i + 1..].iter().any(|b| overlap(a, b)));
+    if !any_overlap {
+        return ranges;
+    }
+
+    ranges.sort_by_key(|r| *r.start());
+    let mut merged: Vec<RangeInclusive<u64>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if overlap(last, &range) => {
+                *last = *last.start()..=*last.end().max(range.end());
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+pub fn content_range(range: &RangeInclusive<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start(), range.end(), len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(
+            parse_range("bytes=0-499", 1000),
+            RangeRequest::Partial(vec![0..=499])
+        );
+        assert_eq!(
+            parse_range("bytes=500-", 1000),
+            RangeRequest::Partial(vec![500..=999])
+        );
+        assert_eq!(
+            parse_range("bytes=-200", 1000),
+            RangeRequest::Partial(vec![800..=999])
+        );
+        assert_eq!(
+            parse_range("bytes=-2000", 1000),
+            RangeRequest::Partial(vec![0..=999])
+        );
+        assert_eq!(
+            parse_range("bytes=900-2000", 1000),
+            RangeRequest::Partial(vec![900..=999])
+        );
+        assert_eq!(
+            parse_range("bytes=0-0, -1", 1000),
+            RangeRequest::Partial(vec![0..=0, 999..=999])
+        );
+        assert_eq!(
+            parse_range("bytes=0-1,2000-3000", 1000),
+            RangeRequest::Partial(vec![0..=1])
+        );
+        // Overlapping ranges are merged
+        assert_eq!(
+            parse_range(&format!("bytes={}", "0-,".repeat(32)), 1000),
+            RangeRequest::Partial(vec![0..=999])
+        );
+        assert_eq!(
+            parse_range("bytes=500-600, 0-99, 550-700, -100", 1000),
+            RangeRequest::Partial(vec![0..=99, 500..=700, 900..=999])
+        );
+    }
+
+    #[test]
+    fn test_parse_range_unsatisfiable() {
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn test_parse_range_ignored() {
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=5-1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=a-b", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes", 1000), RangeRequest::Full);
+        assert_eq!(
+            parse_range(&format!("bytes={}", "0-0,".repeat(40)), 1000),
+            RangeRequest::Full
+        );
+    }
+}
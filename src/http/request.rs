@@ -1,32 +1,98 @@
+use crate::http::BUFFER_SIZE;
+use crate::http::chunked::ChunkedDecoder;
+use crate::http::header::HeaderMap;
 use crate::http::method::Method;
-use std::any::{Any, TypeId};
+use crate::http::url::{Query, decode_segment};
+use std::cell::{RefCell, RefMut};
 use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::{BufRead, Cursor, Read};
+use std::str::FromStr;
 
 #[derive(Debug)]
-pub(crate) struct RequestContext<'a> {
+pub struct RequestContext<'a> {
     request: &'a Request,
-    extensions: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
-    url_vars: HashMap<String, String>,
+    url_vars: HashMap<String, UrlVar>,
+    body: RefCell<RequestBody<'a>>,
 }
 
-impl RequestContext<'_> {
-    pub fn from(request: &Request, url_vars: HashMap<String, String>) -> RequestContext {
+#[derive(Debug)]
+struct UrlVar {
+    raw: String,
+    decoded: String,
+}
+
+impl<'a> RequestContext<'a> {
+    /// `url_vars` hold the raw, still percent-encoded values.
+    pub fn from(
+        request: &'a Request,
+        url_vars: HashMap<String, String>,
+        body: RequestBody<'a>,
+    ) -> RequestContext<'a> {
         RequestContext {
             request,
-            extensions: HashMap::new(),
-            url_vars,
+            url_vars: url_vars
+                .into_iter()
+                .map(|(k, raw)| {
+                    let decoded = decode_segment(&raw);
+                    (k, UrlVar { raw, decoded })
+                })
+                .collect(),
+            body: RefCell::new(body),
         }
     }
 
+    /// Returns the percent-decoded value of a URL variable.
     pub fn get_var(&self, k: &str) -> Option<&str> {
-        self.url_vars.get(k).map(|v| v.as_str())
+        self.url_vars.get(k).map(|v| v.decoded.as_str())
+    }
+
+    /// Returns a URL variable as it appeared in the (normalized) request path.
+    pub fn get_raw_var(&self, k: &str) -> Option<&str> {
+        self.url_vars.get(k).map(|v| v.raw.as_str())
+    }
+
+    /// Parses a URL variable, like an `<id:int>` the route already checked.
+    pub fn parse_var<T: FromStr>(&self, k: &str) -> Option<T> {
+        self.get_var(k)?.parse().ok()
     }
 
     pub fn get_header(&self, k: &str) -> Option<&str> {
-        self.request
-            .headers
-            .get(&k.to_lowercase())
-            .map(|v| v.as_str())
+        self.request.headers.get(k)
+    }
+
+    /// Returns the first value of a query parameter.
+    pub fn get_query(&self, k: &str) -> Option<&str> {
+        self.request.query.get(k)
+    }
+
+    pub fn query(&self) -> &Query {
+        &self.request.query
+    }
+
+    /// Trailers of a streamed body become available once the body has been read to the end.
+    pub fn get_trailer(&self, k: &str) -> Option<String> {
+        if let Some(v) = self.request.trailers.get(k) {
+            return Some(v.to_string());
+        }
+
+        let body = self.body.try_borrow().ok()?;
+        body.trailers().and_then(|t| t.get(k)).map(String::from)
+    }
+
+    /// Request body reader. Unlike `Request::content`, this works for bodies of any size,
+    /// and returns the output of the body decoder if one is set.
+    pub fn body(&self) -> RefMut<'_, RequestBody<'a>> {
+        self.body.borrow_mut()
+    }
+
+    /// Makes `body` return the raw body as transformed by `decoder`.
+    pub fn set_body_decoder(&mut self, decoder: Box<dyn BodyDecoder + 'a>) {
+        let body = self.body.get_mut();
+        body.decoder = Some(decoder);
+        body.decoded = Cursor::default();
+        body.decoder_done = false;
     }
 
     pub fn request(&self) -> &Request {
@@ -34,16 +100,267 @@ impl RequestContext<'_> {
     }
 }
 
+/// Transforms the raw body as it is read, e.g. to remove a content coding.
+pub trait BodyDecoder {
+    /// Takes the next piece of the raw body and returns the output that is ready.
+    /// An empty `input` marks the end of the body.
+    fn decode(&mut self, input: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Reads the request body, first from the buffered part and then lazily from the connection.
+pub struct RequestBody<'a> {
+    buffered: &'a [u8],
+    rdr: &'a mut dyn BufRead,
+    state: &'a mut BodyState,
+    decoder: Option<Box<dyn BodyDecoder + 'a>>,
+    decoded: Cursor<Vec<u8>>,
+    decoder_done: bool,
+}
+
+impl<'a> RequestBody<'a> {
+    pub(crate) fn new(
+        request: &'a Request,
+        rdr: &'a mut dyn BufRead,
+        state: &'a mut BodyState,
+    ) -> RequestBody<'a> {
+        RequestBody {
+            buffered: &request.content,
+            rdr,
+            state,
+            decoder: None,
+            decoded: Cursor::default(),
+            decoder_done: false,
+        }
+    }
+
+    pub fn trailers(&self) -> Option<&HeaderMap> {
+        self.state.trailers()
+    }
+
+    fn read_raw(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.buffered.is_empty() {
+            return self.buffered.read(buf);
+        }
+        self.state.read(self.rdr, buf)
+    }
+}
+
+impl Read for RequestBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.decoder.is_none() {
+            return self.read_raw(buf);
+        }
+
+        loop {
+            let n = self.decoded.read(buf)?;
+            if n > 0 || buf.is_empty() || self.decoder_done {
+                return Ok(n);
+            }
+
+            let mut raw = [0; BUFFER_SIZE];
+            let len = self.read_raw(&mut raw)?;
+            let decoder = self.decoder.as_mut().unwrap();
+            self.decoded = Cursor::new(decoder.decode(&raw[..len])?);
+            self.decoder_done = len == 0;
+        }
+    }
+}
+
+impl Debug for RequestBody<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestBody")
+            .field("buffered", &self.buffered.len())
+            .field("state", &self.state)
+            .field("decoding", &self.decoder.is_some())
+            .finish()
+    }
+}
+
+/// The part of a request body that is still unread on the connection.
+#[derive(Debug, Default)]
+pub(crate) struct BodyState {
+    prefix: Cursor<Vec<u8>>,
+    framing: Framing,
+}
+
+#[derive(Debug, Default)]
+enum Framing {
+    #[default]
+    None,
+    Length(u64),
+    Chunked(ChunkedDecoder),
+}
+
+impl BodyState {
+    pub fn with_length(remaining: u64) -> Self {
+        Self {
+            prefix: Cursor::default(),
+            framing: Framing::Length(remaining),
+        }
+    }
+
+    /// `prefix` holds the part of the body that was already decoded from the connection.
+    pub fn chunked(prefix: Vec<u8>, decoder: ChunkedDecoder) -> Self {
+        Self {
+            prefix: Cursor::new(prefix),
+            framing: Framing::Chunked(decoder),
+        }
+    }
+
+    pub fn read(&mut self, rdr: &mut dyn BufRead, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.prefix.read(buf)?;
+        if n > 0 {
+            return Ok(n);
+        }
+
+        match &mut self.framing {
+            Framing::None => Ok(0),
+            Framing::Length(0) => Ok(0),
+            Framing::Length(remaining) => {
+                let to_read = buf
+                    .len()
+                    .min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                let n = rdr.read(&mut buf[..to_read])?;
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                *remaining -= n as u64;
+                Ok(n)
+            }
+            Framing::Chunked(decoder) => decoder.read(rdr, buf),
+        }
+    }
+
+    /// Discards up to `limit` bytes the handler didn't read, so the next request can be parsed.
+    /// Returns `false` if more is left, in which case the connection can't be reused.
+    pub fn drain(&mut self, rdr: &mut dyn BufRead, limit: u64) -> io::Result<bool> {
+        let mut buf = [0u8; BUFFER_SIZE];
+        let mut total = 0;
+        while total <= limit {
+            let n = self.read(rdr, &mut buf)?;
+            if n == 0 {
+                return Ok(true);
+            }
+            total += n as u64;
+        }
+        Ok(false)
+    }
+
+    /// Trailer fields of a chunked body, once it has been read to the end.
+    pub fn trailers(&self) -> Option<&HeaderMap> {
+        match &self.framing {
+            Framing::Chunked(decoder) if decoder.is_done() => Some(decoder.trailers()),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Request {
     pub method: Method,
+    /// The request target as sent by the client.
     pub url: String,
-    pub headers: HashMap<String, String>,
+    /// Normalized path; reserved characters are still percent-encoded.
+    pub path: String,
+    pub query: Query,
+    pub headers: HeaderMap,
+    pub trailers: HeaderMap,
     pub content: Vec<u8>,
 }
 
 impl Request {
     pub fn get_header(&self, k: &str) -> Option<&str> {
-        self.headers.get(&k.to_lowercase()).map(|v| v.as_str())
+        self.headers.get(k)
+    }
+}
+
+#[cfg(test)]
+impl Request {
+    /// A request for `target` without a body.
+    pub(crate) fn for_test(method: Method, target: &str, headers: &[(&str, &str)]) -> Self {
+        let (path, query) = crate::http::url::split_target(target);
+        Request {
+            method,
+            url: target.to_string(),
+            path: crate::http::url::normalize_path(path).unwrap(),
+            query: query.map(Query::parse).unwrap_or_default(),
+            headers: headers.iter().copied().collect(),
+            trailers: HeaderMap::new(),
+            content: Vec::new(),
+        }
+    }
+}
+
+/// Runs `f` with a context for `request`, which has no body left to read.
+#[cfg(test)]
+pub(crate) fn with_context<R>(request: &Request, f: impl FnOnce(&mut RequestContext) -> R) -> R {
+    let mut rdr: &[u8] = &[];
+    let mut state = BodyState::default();
+    let body = RequestBody::new(request, &mut rdr, &mut state);
+    f(&mut RequestContext::from(request, HashMap::new(), body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_body(request: &Request, rdr: &mut dyn BufRead, state: &mut BodyState) -> Vec<u8> {
+        let mut content = Vec::new();
+        RequestBody::new(request, rdr, state)
+            .read_to_end(&mut content)
+            .unwrap();
+        content
+    }
+
+    #[test]
+    fn test_read_length_body() {
+        let mut request = Request::for_test(Method::POST, "/", &[]);
+        request.content = b"hello ".to_vec();
+        let mut state = BodyState::with_length(5);
+        let mut rdr = &b"worldGET"[..];
+
+        assert_eq!(read_body(&request, &mut rdr, &mut state), b"hello world");
+        assert_eq!(rdr, b"GET");
+    }
+
+    #[test]
+    fn test_read_chunked_body() {
+        let mut rdr = &b"4\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\nGET"[..];
+        // The first chunk was decoded while reading the request
+        let mut decoder = ChunkedDecoder::default();
+        let mut prefix = [0u8; 4];
+        decoder.read(&mut rdr, &mut prefix).unwrap();
+        let mut state = BodyState::chunked(prefix.to_vec(), decoder);
+
+        let request = Request::for_test(Method::POST, "/", &[]);
+        assert_eq!(state.trailers(), None);
+        assert_eq!(read_body(&request, &mut rdr, &mut state), b"Wikipedia");
+        assert_eq!(state.trailers().unwrap().get("Expires"), Some("never"));
+        assert_eq!(rdr, b"GET");
+    }
+
+    #[test]
+    fn test_read_truncated_body() {
+        let request = Request::for_test(Method::POST, "/", &[]);
+        let mut state = BodyState::with_length(10);
+        let mut rdr = &b"short"[..];
+
+        let err = RequestBody::new(&request, &mut rdr, &mut state)
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut state = BodyState::with_length(5);
+        let mut rdr = &b"helloGET"[..];
+        assert!(state.drain(&mut rdr, 5).unwrap());
+        assert_eq!(rdr, b"GET");
+
+        let raw = vec![b'a'; BUFFER_SIZE * 2];
+        let mut state = BodyState::with_length(raw.len() as u64);
+        let mut rdr = &raw[..];
+        assert!(!state.drain(&mut rdr, 10).unwrap());
     }
 }
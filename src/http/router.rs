@@ -0,0 +1,1217 @@
+use crate::http::handler::HandlerFunc;
+use crate::http::method::Method;
+use crate::http::middleware::Middleware;
+use crate::http::url::decode_segment;
+use regex::Regex;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// URL variables captured by a route, with their raw values.
+pub type Vars = Vec<(String, String)>;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RouteError {
+    #[error("invalid route {pattern:?}: {reason}")]
+    InvalidPattern { pattern: String, reason: String },
+    #[error("route {pattern:?} has more than one variable named {name:?}")]
+    DuplicateVar { pattern: String, name: String },
+    #[error("route {pattern:?} conflicts with {existing:?}, which matches the same paths")]
+    Conflict { pattern: String, existing: String },
+}
+
+pub struct Handler {
+    pub(crate) method: Method,
+    pub f: HandlerFunc,
+    /// Middleware of the routers the handler was nested through, outermost
+    /// first, then that of the route.
+    pub(crate) layers: Vec<Arc<[Box<dyn Middleware>]>>,
+}
+
+impl Handler {
+    fn new(method: Method, f: HandlerFunc, middlewares: Vec<Box<dyn Middleware>>) -> Self {
+        let mut layers = Vec::new();
+        if !middlewares.is_empty() {
+            layers.push(middlewares.into());
+        }
+        Self { method, f, layers }
+    }
+
+    fn conflicts(&self, other: &Handler) -> bool {
+        self.method == other.method
+    }
+}
+
+/// Handlers with the middleware that runs for them, built on their own and
+/// nested below a prefix into a `Server` or another router. A `Server` has a
+/// router of its own, whose middleware runs for every handler.
+#[derive(Default)]
+pub struct Router {
+    pub(crate) routes: RouteTree<Handler>,
+    pub(crate) middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler for paths matching `pattern`, in which a segment may
+    /// contain a variable, like `/files/<name>.txt`. Variables may be typed
+    /// (`<id:int>`, `<id:uuid>`, `<slug:regex([a-z-]+)>`) and a path that doesn't
+    /// fit the type doesn't match; `<path..>` matches the rest of the path, and a
+    /// segment ending with `?` is optional. See `RouteTree::insert`.
+    ///
+    /// Fails if the pattern is invalid or uses a variable name twice, or if a
+    /// handler for the same method was registered for an equivalent pattern.
+    pub fn add_handler(
+        &mut self,
+        m: Method,
+        pattern: &str,
+        f: HandlerFunc,
+    ) -> Result<(), RouteError> {
+        self.add_handler_with(m, pattern, Vec::new(), f)
+    }
+
+    /// Registers a handler like `add_handler`, with middleware that runs only
+    /// for it, inside that of the routers.
+    pub fn add_handler_with(
+        &mut self,
+        m: Method,
+        pattern: &str,
+        middlewares: Vec<Box<dyn Middleware>>,
+        f: HandlerFunc,
+    ) -> Result<(), RouteError> {
+        self.routes
+            .insert(pattern, Handler::new(m, f, middlewares), Handler::conflicts)
+    }
+
+    /// Registers a GET handler for `prefix` and everything below it. The rest
+    /// of the path, starting with `/`, is passed in the `path` URL variable.
+    pub fn mount(&mut self, prefix: &str, f: HandlerFunc) -> Result<(), RouteError> {
+        self.routes.insert_prefix(
+            prefix,
+            "path",
+            Handler::new(Method::GET, f, Vec::new()),
+            Handler::conflicts,
+        )
+    }
+
+    /// Adds middleware that runs only for the handlers of this router, in the
+    /// order it was added.
+    pub fn add_middleware(&mut self, m: Box<dyn Middleware>) {
+        self.middlewares.push(m);
+    }
+
+    /// Moves the handlers of `router` into this one, below `prefix`: with a
+    /// prefix of `/api/v1`, its `/users/<id>` is matched as `/api/v1/users/<id>`,
+    /// and its `/` as `/api/v1`. Its middleware runs for them, inside this
+    /// router's. Fails like `add_handler`.
+    pub fn nest(&mut self, prefix: &str, router: Router) -> Result<(), RouteError> {
+        let layer: Arc<[Box<dyn Middleware>]> = router.middlewares.into();
+        self.routes.nest(
+            prefix,
+            router.routes,
+            |mut handler| {
+                if !layer.is_empty() {
+                    handler.layers.insert(0, Arc::clone(&layer));
+                }
+                handler
+            },
+            Handler::conflicts,
+        )
+    }
+}
+
+/// Routes in a prefix tree over the segments of their patterns.
+///
+/// Paths are matched segment by segment, so the cost depends on the path rather
+/// than on the number of routes, and the result doesn't depend on the order of
+/// registration: at each segment, static text takes precedence over a variable,
+/// and a variable over a wildcard matching the rest of the path. Variables and
+/// wildcards that rank the same are tried in the order of their text.
+pub struct RouteTree<T> {
+    root: Node<T>,
+}
+
+struct Node<T> {
+    // Shared by the routes a pattern with optional segments expands to
+    values: Vec<Arc<Route<T>>>,
+    statics: HashMap<String, Node<T>>,
+    params: Vec<Param<T>>,
+    wildcards: Vec<Wildcard<T>>,
+}
+
+struct Route<T> {
+    pattern: String,
+    /// The variable of a route added with `insert_prefix`.
+    prefix_var: Option<String>,
+    value: T,
+}
+
+/// A route pattern parsed and validated, before it is added to a tree.
+#[derive(Clone)]
+struct Parsed {
+    pattern: String,
+    prefix_var: Option<String>,
+    /// The segments of the paths it matches, one list for each combination of
+    /// optional segments.
+    variants: Vec<Vec<Segment>>,
+    /// Whether a catch-all at the end also matches nothing.
+    optional: bool,
+}
+
+impl Parsed {
+    fn pattern(pattern: &str) -> Result<Self, RouteError> {
+        let segments = parse_pattern(pattern).map_err(|reason| RouteError::InvalidPattern {
+            pattern: pattern.to_string(),
+            reason,
+        })?;
+
+        let mut names = Vec::new();
+        for (segment, _) in &segments {
+            let name = match segment {
+                Segment::Static(_) => continue,
+                Segment::Param(spec) => &spec.name,
+                Segment::CatchAll(name) => name,
+            };
+            if names.contains(&name) {
+                return Err(RouteError::DuplicateVar {
+                    pattern: pattern.to_string(),
+                    name: name.clone(),
+                });
+            }
+            names.push(name);
+        }
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            prefix_var: None,
+            variants: variants(&segments)
+                .into_iter()
+                .map(|v| v.into_iter().cloned().collect())
+                .collect(),
+            optional: segments.last().is_some_and(|(_, optional)| *optional),
+        })
+    }
+
+    fn prefix(prefix: &str, var: &str) -> Self {
+        let segments = split(prefix.trim_end_matches('/'))
+            .into_iter()
+            .map(|s| Segment::Static(s.to_string()))
+            .chain([Segment::CatchAll(var.to_string())])
+            .collect();
+        Self {
+            pattern: prefix.to_string(),
+            prefix_var: Some(var.to_string()),
+            variants: vec![segments],
+            optional: true,
+        }
+    }
+}
+
+/// A segment with a variable, optionally between static text, like `<id>.json`.
+struct Param<T> {
+    spec: ParamSpec,
+    node: Node<T>,
+}
+
+/// Matches the rest of the path.
+struct Wildcard<T> {
+    name: String,
+    /// The value starts with `/`, as for `Server::mount`.
+    slash: bool,
+    /// Also matches when nothing is left, without setting the variable.
+    optional: bool,
+    values: Vec<Arc<Route<T>>>,
+}
+
+/// What a variable matches, besides non-empty text without `/`.
+#[derive(Debug, Clone)]
+enum Constraint {
+    Any,
+    Int,
+    Uuid,
+    Regex(Regex),
+}
+
+impl Constraint {
+    fn parse(spec: &str) -> Result<Self, String> {
+        match spec {
+            "" => Ok(Self::Any),
+            "int" => Ok(Self::Int),
+            "uuid" => Ok(Self::Uuid),
+            _ => {
+                let re = spec
+                    .strip_prefix("regex(")
+                    .and_then(|s| s.strip_suffix(')'))
+                    .ok_or_else(|| format!("unknown variable type {}", spec))?;
+                Regex::new(&format!("^(?:{})$", re))
+                    .map(Self::Regex)
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// The type as written in the pattern, regexes in their anchored form.
+    fn source(&self) -> &str {
+        match self {
+            Self::Any => "",
+            Self::Int => "int",
+            Self::Uuid => "uuid",
+            Self::Regex(re) => re.as_str(),
+        }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Int => value.parse::<i64>().is_ok(),
+            Self::Uuid => is_uuid(value),
+            Self::Regex(re) => re.is_match(value),
+        }
+    }
+}
+
+impl PartialEq for Constraint {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Regex(a), Self::Regex(b)) => a.as_str() == b.as_str(),
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ParamSpec {
+    prefix: String,
+    name: String,
+    constraint: Constraint,
+    suffix: String,
+}
+
+impl ParamSpec {
+    /// The raw value of the variable. The constraint is checked against the
+    /// decoded value, which is what handlers get.
+    fn capture<'s>(&self, segment: &'s str) -> Option<&'s str> {
+        segment
+            .strip_prefix(self.prefix.as_str())?
+            .strip_suffix(self.suffix.as_str())
+            .filter(|v| {
+                !v.is_empty()
+                    && (self.constraint == Constraint::Any
+                        || self.constraint.matches(&decode_segment(v)))
+            })
+    }
+
+    /// Whether both match the same segments.
+    fn equivalent(&self, other: &ParamSpec) -> bool {
+        self.prefix == other.prefix
+            && self.suffix == other.suffix
+            && self.constraint == other.constraint
+    }
+
+    /// Variables with more static text around them, then those with a
+    /// constraint, are tried first.
+    fn rank(&self) -> (usize, bool) {
+        (
+            self.prefix.len() + self.suffix.len(),
+            self.constraint != Constraint::Any,
+        )
+    }
+
+    /// Sorts by rank, then by text, so that variables never tie.
+    fn precedence(&self) -> impl Ord + '_ {
+        (
+            Reverse(self.rank()),
+            self.prefix.as_str(),
+            self.suffix.as_str(),
+            self.constraint.source(),
+            self.name.as_str(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Static(String),
+    Param(ParamSpec),
+    /// `<name..>`: the rest of the path.
+    CatchAll(String),
+}
+
+/// A piece of a segment while parsing.
+enum Part {
+    Text(String),
+    Var {
+        name: String,
+        catch_all: bool,
+        constraint: Constraint,
+    },
+}
+
+impl<T> Default for RouteTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> RouteTree<T> {
+    pub fn new() -> Self {
+        Self { root: Node::new() }
+    }
+
+    /// Adds a route for `pattern`, a path whose segments may contain a variable:
+    ///
+    /// - `<name>` matches any non-empty text without `/`
+    /// - `<name:int>` an integer, `<name:uuid>` a UUID, and `<name:regex(...)>`
+    ///   text the regular expression matches in full
+    /// - `<name..>`, a whole last segment, matches the rest of the path
+    ///
+    /// A segment ending with `?`, like `/posts/<page:int>?`, may be left out.
+    ///
+    /// Fails if the pattern is invalid, or if it matches the same paths as the
+    /// pattern of an earlier route whose value `conflicts` with this one.
+    pub fn insert(
+        &mut self,
+        pattern: &str,
+        value: T,
+        conflicts: impl Fn(&T, &T) -> bool,
+    ) -> Result<(), RouteError> {
+        let parsed = Parsed::pattern(pattern)?;
+        self.check(&parsed, &value, &conflicts)?;
+        self.add(parsed, value);
+        Ok(())
+    }
+
+    /// Adds a route for `prefix` and every path below it. The rest of the
+    /// path, starting with `/`, is captured as `var`. Fails like `insert`.
+    pub fn insert_prefix(
+        &mut self,
+        prefix: &str,
+        var: &str,
+        value: T,
+        conflicts: impl Fn(&T, &T) -> bool,
+    ) -> Result<(), RouteError> {
+        let parsed = Parsed::prefix(prefix, var);
+        self.check(&parsed, &value, &conflicts)?;
+        self.add(parsed, value);
+        Ok(())
+    }
+
+    /// Moves the routes of `other` into this tree, below `prefix`, passing
+    /// their values through `map`. Fails like `insert`, in which case none of
+    /// the routes are added.
+    pub fn nest(
+        &mut self,
+        prefix: &str,
+        other: RouteTree<T>,
+        map: impl Fn(T) -> T,
+        conflicts: impl Fn(&T, &T) -> bool,
+    ) -> Result<(), RouteError> {
+        let mut routes: Vec<Arc<Route<T>>> = Vec::new();
+        other.root.into_routes(&mut routes);
+
+        let prefix = prefix.trim_end_matches('/');
+        let mut parsed = Vec::new();
+        let mut values = Vec::new();
+        for route in routes {
+            // The other references were dropped with the rest of the tree
+            let Ok(route) = Arc::try_unwrap(route) else {
+                unreachable!()
+            };
+            let pattern = match route.pattern.as_str() {
+                "" | "/" if !prefix.is_empty() => prefix.to_string(),
+                pattern => format!("{}{}", prefix, pattern),
+            };
+            parsed.push(match route.prefix_var {
+                Some(var) => Parsed::prefix(&pattern, &var),
+                None => Parsed::pattern(&pattern)?,
+            });
+            values.push(map(route.value));
+        }
+
+        // Prefixed, two of the routes may now conflict with each other too
+        let mut nested = RouteTree::new();
+        for (i, route) in parsed.iter().enumerate() {
+            self.check(route, &values[i], &conflicts)?;
+            nested.check(route, &i, |a: &usize, b: &usize| {
+                conflicts(&values[*a], &values[*b])
+            })?;
+            nested.add(route.clone(), i);
+        }
+
+        for (route, value) in parsed.into_iter().zip(values) {
+            self.add(route, value);
+        }
+        Ok(())
+    }
+
+    /// Fails if `parsed` matches the same paths as a route whose value `conflicts`.
+    fn check(
+        &self,
+        parsed: &Parsed,
+        value: &T,
+        conflicts: impl Fn(&T, &T) -> bool,
+    ) -> Result<(), RouteError> {
+        for variant in &parsed.variants {
+            let variant: Vec<&Segment> = variant.iter().collect();
+            if let Some(existing) = self
+                .root
+                .find_conflict(&variant, parsed.optional, &|other| conflicts(other, value))
+            {
+                return Err(RouteError::Conflict {
+                    pattern: parsed.pattern.clone(),
+                    existing: existing.pattern.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, parsed: Parsed, value: T) {
+        // A prefix route captures the rest of the path with its leading `/`
+        let slash = parsed.prefix_var.is_some();
+        let route = Arc::new(Route {
+            pattern: parsed.pattern,
+            prefix_var: parsed.prefix_var,
+            value,
+        });
+        for variant in &parsed.variants {
+            let mut node = &mut self.root;
+            let mut catch_all = None;
+            for segment in variant {
+                match segment {
+                    Segment::Static(text) => {
+                        node = node.statics.entry(text.clone()).or_insert_with(Node::new)
+                    }
+                    Segment::Param(spec) => node = node.param(spec),
+                    Segment::CatchAll(name) => catch_all = Some(name),
+                }
+            }
+
+            match catch_all {
+                Some(name) => node
+                    .wildcard(name, slash, parsed.optional)
+                    .push(Arc::clone(&route)),
+                None => node.values.push(Arc::clone(&route)),
+            }
+        }
+    }
+
+    /// The values of the routes matching `path`, most specific first, with
+    /// their variables.
+    pub fn lookup(&self, path: &str) -> Vec<(&T, Vars)> {
+        let mut found = Vec::new();
+        self.root.lookup(&split(path), &mut Vec::new(), &mut found);
+        found
+    }
+
+    /// All values in the tree.
+    pub fn values(&self) -> Vec<&T> {
+        let mut routes: Vec<&Arc<Route<T>>> = Vec::new();
+        self.root.collect(&mut routes);
+        let mut unique: Vec<&T> = Vec::new();
+        for route in routes {
+            if !unique.iter().any(|v| std::ptr::eq(*v, &route.value)) {
+                unique.push(&route.value);
+            }
+        }
+        unique
+    }
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Self {
+            values: Vec::new(),
+            statics: HashMap::new(),
+            params: Vec::new(),
+            wildcards: Vec::new(),
+        }
+    }
+
+    fn param(&mut self, spec: &ParamSpec) -> &mut Node<T> {
+        let i = match self.params.iter().position(|p| p.spec == *spec) {
+            Some(i) => i,
+            None => {
+                let i = self
+                    .params
+                    .partition_point(|p| p.spec.precedence() <= spec.precedence());
+                self.params.insert(
+                    i,
+                    Param {
+                        spec: spec.clone(),
+                        node: Node::new(),
+                    },
+                );
+                i
+            }
+        };
+        &mut self.params[i].node
+    }
+
+    fn wildcard(&mut self, name: &str, slash: bool, optional: bool) -> &mut Vec<Arc<Route<T>>> {
+        let i = match self
+            .wildcards
+            .iter()
+            .position(|w| w.name == name && w.slash == slash && w.optional == optional)
+        {
+            Some(i) => i,
+            None => {
+                // One that needs a segment is more specific
+                let i = self.wildcards.partition_point(|w| {
+                    (w.optional, &w.name[..], w.slash) <= (optional, name, slash)
+                });
+                self.wildcards.insert(
+                    i,
+                    Wildcard {
+                        name: name.to_string(),
+                        slash,
+                        optional,
+                        values: Vec::new(),
+                    },
+                );
+                i
+            }
+        };
+        &mut self.wildcards[i].values
+    }
+
+    fn lookup<'t>(&'t self, segments: &[&str], vars: &mut Vars, found: &mut Vec<(&'t T, Vars)>) {
+        let found_all =
+            |routes: &'t [Arc<Route<T>>], vars: &Vars, found: &mut Vec<(&'t T, Vars)>| {
+                found.extend(routes.iter().map(|r| (&r.value, vars.clone())));
+            };
+
+        let Some((segment, rest)) = segments.split_first() else {
+            found_all(&self.values, vars, found);
+            for wildcard in self.wildcards.iter().filter(|w| w.optional) {
+                found_all(&wildcard.values, vars, found);
+            }
+            return;
+        };
+
+        if let Some(child) = self.statics.get(*segment) {
+            child.lookup(rest, vars, found);
+        }
+
+        for param in &self.params {
+            if let Some(value) = param.spec.capture(segment) {
+                vars.push((param.spec.name.clone(), value.to_string()));
+                param.node.lookup(rest, vars, found);
+                vars.pop();
+            }
+        }
+
+        // Joined only where needed, so matching stays linear in the path length
+        if self.wildcards.is_empty() {
+            return;
+        }
+        let rest_of_path = segments.join("/");
+        for wildcard in &self.wildcards {
+            let value = if wildcard.slash {
+                format!("/{}", rest_of_path)
+            } else {
+                rest_of_path.clone()
+            };
+
+            let mut vars = vars.clone();
+            if !value.is_empty() {
+                vars.push((wildcard.name.clone(), value));
+            } else if !wildcard.optional {
+                continue;
+            }
+            found_all(&wildcard.values, &vars, found);
+        }
+    }
+
+    /// A route with a value that `conflicts`, matching the same paths as
+    /// `variant` regardless of the variable names. A catch-all at its end
+    /// is `optional` or not. Like other optional segments, an optional
+    /// catch-all conflicts with a route that ends where it starts.
+    fn find_conflict<'t>(
+        &'t self,
+        variant: &[&Segment],
+        optional: bool,
+        conflicts: &dyn Fn(&T) -> bool,
+    ) -> Option<&'t Route<T>> {
+        let find = |routes: &'t [Arc<Route<T>>]| -> Option<&'t Route<T>> {
+            routes.iter().map(|r| &**r).find(|r| conflicts(&r.value))
+        };
+
+        let optional_wildcards = || {
+            self.wildcards
+                .iter()
+                .filter(|w| w.optional)
+                .find_map(|w| find(&w.values))
+        };
+
+        match variant.split_first() {
+            None => find(&self.values).or_else(optional_wildcards),
+            Some((Segment::Static(text), rest)) => self
+                .statics
+                .get(text)?
+                .find_conflict(rest, optional, conflicts),
+            Some((Segment::Param(spec), rest)) => self
+                .params
+                .iter()
+                .filter(|p| p.spec.equivalent(spec))
+                .find_map(|p| p.node.find_conflict(rest, optional, conflicts)),
+            Some((Segment::CatchAll(_), _)) if optional => {
+                optional_wildcards().or_else(|| find(&self.values))
+            }
+            Some((Segment::CatchAll(_), _)) => self
+                .wildcards
+                .iter()
+                .filter(|w| !w.optional)
+                .find_map(|w| find(&w.values)),
+        }
+    }
+
+    /// Takes the routes out of the tree, once each.
+    fn into_routes(self, routes: &mut Vec<Arc<Route<T>>>) {
+        let mut add = |route: Arc<Route<T>>| {
+            if !routes.iter().any(|r| Arc::ptr_eq(r, &route)) {
+                routes.push(route);
+            }
+        };
+        self.values.into_iter().for_each(&mut add);
+        for wildcard in self.wildcards {
+            wildcard.values.into_iter().for_each(&mut add);
+        }
+        for child in self.statics.into_values() {
+            child.into_routes(routes);
+        }
+        for param in self.params {
+            param.node.into_routes(routes);
+        }
+    }
+
+    fn collect<'t>(&'t self, routes: &mut Vec<&'t Arc<Route<T>>>) {
+        routes.extend(&self.values);
+        for child in self.statics.values() {
+            child.collect(routes);
+        }
+        for param in &self.params {
+            param.node.collect(routes);
+        }
+        for wildcard in &self.wildcards {
+            routes.extend(&wildcard.values);
+        }
+    }
+}
+
+/// Parses a route pattern into its segments, each with whether it is optional.
+fn parse_pattern(pattern: &str) -> Result<Vec<(Segment, bool)>, String> {
+    let mut segments = Vec::new();
+    if pattern.is_empty() {
+        return Ok(segments);
+    }
+
+    let mut chars = pattern
+        .strip_prefix('/')
+        .unwrap_or(pattern)
+        .chars()
+        .peekable();
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some('<') => {
+                if !text.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                }
+                parts.push(parse_var(&mut chars)?);
+            }
+            c @ (Some('/') | None) => {
+                let optional = text.ends_with('?');
+                if optional {
+                    text.pop();
+                }
+                if !text.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                }
+                segments.push((to_segment(std::mem::take(&mut parts))?, optional));
+                if c.is_none() {
+                    break;
+                }
+            }
+            Some(c) => text.push(c),
+        }
+    }
+
+    let last = segments.len() - 1;
+    if segments[..last]
+        .iter()
+        .any(|(s, _)| matches!(s, Segment::CatchAll(_)))
+    {
+        return Err("a catch-all variable must be the last segment".to_string());
+    }
+    Ok(segments)
+}
+
+/// Parses a variable after its `<`.
+fn parse_var(chars: &mut Peekable<Chars>) -> Result<Part, String> {
+    let mut name = String::new();
+    while let Some(&c) = chars.peek() {
+        if !(c.is_ascii_lowercase() || (c.is_ascii_digit() && !name.is_empty())) {
+            break;
+        }
+        name.push(c);
+        chars.next();
+    }
+    if name.is_empty() {
+        return Err("a variable needs a name of lowercase letters and digits".to_string());
+    }
+
+    let catch_all = chars.next_if_eq(&'.').is_some();
+    if catch_all && chars.next() != Some('.') {
+        return Err(format!("expected <{}..>", name));
+    }
+
+    let mut spec = String::new();
+    if chars.next_if_eq(&':').is_some() {
+        // The type ends at a `>` outside the parentheses of a regular expression
+        let mut depth = 0;
+        loop {
+            match chars.peek() {
+                None => break,
+                Some('>') if depth == 0 => break,
+                Some('(') => depth += 1,
+                Some(')') => depth -= 1,
+                Some('\\') => spec.extend(chars.next()),
+                _ => {}
+            }
+            spec.extend(chars.next());
+        }
+    }
+    if chars.next() != Some('>') {
+        return Err(format!("variable {} isn't closed with >", name));
+    }
+    if catch_all && !spec.is_empty() {
+        return Err(format!("catch-all variable {} can't have a type", name));
+    }
+
+    Ok(Part::Var {
+        name,
+        catch_all,
+        constraint: Constraint::parse(&spec)?,
+    })
+}
+
+fn to_segment(parts: Vec<Part>) -> Result<Segment, String> {
+    let mut prefix = String::new();
+    let mut suffix = String::new();
+    let mut var = None;
+    for part in parts {
+        match (part, &var) {
+            (Part::Text(text), None) => prefix = text,
+            (Part::Text(text), Some(_)) => suffix = text,
+            (v @ Part::Var { .. }, None) => var = Some(v),
+            (Part::Var { .. }, Some(_)) => {
+                return Err("a segment can only have one variable".to_string());
+            }
+        }
+    }
+
+    match var {
+        None => Ok(Segment::Static(prefix)),
+        Some(Part::Var {
+            name,
+            catch_all: true,
+            ..
+        }) => {
+            if !prefix.is_empty() || !suffix.is_empty() {
+                return Err(format!(
+                    "catch-all variable {} must be a whole segment",
+                    name
+                ));
+            }
+            Ok(Segment::CatchAll(name))
+        }
+        Some(Part::Var {
+            name, constraint, ..
+        }) => Ok(Segment::Param(ParamSpec {
+            prefix,
+            name,
+            constraint,
+            suffix,
+        })),
+        Some(Part::Text(_)) => unreachable!(),
+    }
+}
+
+/// The patterns with and without each optional segment. An optional catch-all
+/// is left to the wildcard, which can match nothing.
+fn variants(segments: &[(Segment, bool)]) -> Vec<Vec<&Segment>> {
+    let mut variants = vec![Vec::new()];
+    for (segment, optional) in segments {
+        if *optional && !matches!(segment, Segment::CatchAll(_)) {
+            let with: Vec<_> = variants
+                .iter()
+                .map(|v| {
+                    let mut v = v.clone();
+                    v.push(segment);
+                    v
+                })
+                .collect();
+            variants.extend(with);
+        } else {
+            for v in &mut variants {
+                v.push(segment);
+            }
+        }
+    }
+    variants
+}
+
+/// Splits a path into the segments after its leading `/`; `/` is one empty segment.
+fn split(path: &str) -> Vec<&str> {
+    if path.is_empty() {
+        return Vec::new();
+    }
+    path.strip_prefix('/').unwrap_or(path).split('/').collect()
+}
+
+/// Whether `s` is a UUID in the 8-4-4-4-12 hex digit form.
+fn is_uuid(s: &str) -> bool {
+    let groups: Vec<&str> = s.split('-').collect();
+    groups.iter().map(|g| g.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|g| g.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::Response;
+    use crate::http::middleware::Next;
+    use crate::http::request::RequestContext;
+
+    fn lookup<'t>(tree: &'t RouteTree<&'static str>, path: &str) -> Vec<(&'t str, Vars)> {
+        tree.lookup(path)
+            .into_iter()
+            .map(|(value, vars)| (*value, vars))
+            .collect()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vars {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let mut tree = RouteTree::new();
+        tree.insert_prefix("/files", "path", "mount", |_, _| true)
+            .unwrap();
+        tree.insert("/files/<name>", "param", |_, _| true).unwrap();
+        tree.insert("/files/<name>.txt", "text", |_, _| true)
+            .unwrap();
+        tree.insert("/files/index", "static", |_, _| true).unwrap();
+
+        assert_eq!(
+            lookup(&tree, "/files/index"),
+            vec![
+                ("static", vars(&[])),
+                ("param", vars(&[("name", "index")])),
+                ("mount", vars(&[("path", "/index")])),
+            ]
+        );
+        assert_eq!(
+            lookup(&tree, "/files/a.txt")[..2],
+            [
+                ("text", vars(&[("name", "a")])),
+                ("param", vars(&[("name", "a.txt")])),
+            ]
+        );
+        assert_eq!(lookup(&tree, "/files"), vec![("mount", vars(&[]))]);
+        assert_eq!(
+            lookup(&tree, "/files/a/b"),
+            vec![("mount", vars(&[("path", "/a/b")]))]
+        );
+        assert!(lookup(&tree, "/filesx").is_empty());
+    }
+
+    #[test]
+    fn test_precedence_ties() {
+        let patterns = [
+            "/<a>x",
+            "/x<a>",
+            "/<a:regex(x+)>",
+            "/<a:regex(.+)>",
+            "/<b..>",
+            "/<a..>",
+        ];
+        let values = ["a", "b", "c", "d", "e", "f"];
+        let build = |order: &[usize]| {
+            let mut tree = RouteTree::new();
+            for &i in order {
+                tree.insert(patterns[i], values[i], |_, _| false).unwrap();
+            }
+            lookup(&tree, "/xx")
+                .into_iter()
+                .map(|(m, _)| m.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let forward = build(&[0, 1, 2, 3, 4, 5]);
+        assert_eq!(forward, build(&[5, 4, 3, 2, 1, 0]));
+        assert_eq!(forward, build(&[2, 5, 0, 3, 1, 4]));
+        assert_eq!(forward, ["a", "b", "d", "c", "f", "e"]);
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut tree = RouteTree::new();
+        tree.insert("/", "index", |_, _| true).unwrap();
+        tree.insert("/echo/<s>", "echo", |_, _| true).unwrap();
+        tree.insert_prefix("/", "path", "root", |_, _| true)
+            .unwrap();
+
+        assert_eq!(
+            lookup(&tree, "/"),
+            vec![("index", vars(&[])), ("root", vars(&[("path", "/")]))]
+        );
+        assert_eq!(
+            lookup(&tree, "/echo/abc")[0],
+            ("echo", vars(&[("s", "abc")]))
+        );
+        // A variable doesn't match an empty segment
+        assert_eq!(
+            lookup(&tree, "/echo/"),
+            vec![("root", vars(&[("path", "/echo/")]))]
+        );
+        assert_eq!(tree.values().len(), 3);
+    }
+
+    #[test]
+    fn test_types() {
+        let mut tree = RouteTree::new();
+        tree.insert("/users/<id:int>", "int", |_, _| true).unwrap();
+        tree.insert("/users/<id:uuid>", "uuid", |_, _| true)
+            .unwrap();
+        tree.insert("/users/<name:regex([a-z]+(-[a-z]+)*)>", "slug", |_, _| true)
+            .unwrap();
+        tree.insert("/users/<name>", "any", |_, _| true).unwrap();
+
+        let first = |path| lookup(&tree, path).first().map(|(v, _)| *v);
+        assert_eq!(first("/users/-42"), Some("int"));
+        assert_eq!(
+            first("/users/123e4567-e89b-12d3-a456-426614174000"),
+            Some("uuid")
+        );
+        assert_eq!(first("/users/jane-doe"), Some("slug"));
+        assert_eq!(first("/users/Jane_Doe"), Some("any"));
+        assert_eq!(first("/users/12x"), Some("any"));
+
+        // Constraints apply to the decoded value; the raw one is captured
+        let mut tree = RouteTree::new();
+        tree.insert("/tags/<t:regex([a-zé ]+)>", "tag", |_, _| true)
+            .unwrap();
+        tree.insert("/p/<p:regex([a-z0-9%]+)>", "p", |_, _| true)
+            .unwrap();
+        assert_eq!(
+            lookup(&tree, "/tags/caf%C3%A9%20au%20lait"),
+            vec![("tag", vars(&[("t", "caf%C3%A9%20au%20lait")]))]
+        );
+        assert!(lookup(&tree, "/p/a%2Fb").is_empty());
+        assert!(lookup(&tree, "/p/a%20b").is_empty());
+        assert_eq!(
+            lookup(&tree, "/p/a%32b"),
+            vec![("p", vars(&[("p", "a%32b")]))]
+        );
+    }
+
+    #[test]
+    fn test_catch_all_and_optional() {
+        let mut tree = RouteTree::new();
+        tree.insert("/files/<path..>", "files", |_, _| true)
+            .unwrap();
+        tree.insert("/docs/<path..>?", "docs", |_, _| true).unwrap();
+        tree.insert("/posts/<page:int>?", "posts", |_, _| true)
+            .unwrap();
+        tree.insert(
+            "/archive/<year:int>/<month:int>?/index",
+            "archive",
+            |_, _| true,
+        )
+        .unwrap();
+
+        assert_eq!(
+            lookup(&tree, "/files/a/b/c.txt"),
+            vec![("files", vars(&[("path", "a/b/c.txt")]))]
+        );
+        assert!(lookup(&tree, "/files").is_empty());
+        assert_eq!(lookup(&tree, "/docs"), vec![("docs", vars(&[]))]);
+        assert_eq!(lookup(&tree, "/posts"), vec![("posts", vars(&[]))]);
+        assert_eq!(
+            lookup(&tree, "/posts/2"),
+            vec![("posts", vars(&[("page", "2")]))]
+        );
+        assert!(lookup(&tree, "/posts/two").is_empty());
+        assert_eq!(
+            lookup(&tree, "/archive/2024/index"),
+            vec![("archive", vars(&[("year", "2024")]))]
+        );
+        assert_eq!(
+            lookup(&tree, "/archive/2024/5/index"),
+            vec![("archive", vars(&[("year", "2024"), ("month", "5")]))]
+        );
+        assert_eq!(tree.values().len(), 4);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut tree = RouteTree::new();
+        let same = |a: &&str, b: &&str| a == b;
+        tree.insert("/users/<id:int>", "GET", same).unwrap();
+        tree.insert("/users", "GET", same).unwrap();
+        tree.insert("/users/<id:int>", "POST", same).unwrap();
+        tree.insert("/users/<name>", "GET", same).unwrap();
+        tree.insert_prefix("/files", "path", "GET", same).unwrap();
+
+        assert!(matches!(
+            tree.insert("/users/<id:regex(()>", "GET", same),
+            Err(RouteError::InvalidPattern { .. })
+        ));
+        assert_eq!(
+            tree.insert("/users/<id>/<id:int>", "GET", same),
+            Err(RouteError::DuplicateVar {
+                pattern: "/users/<id>/<id:int>".to_string(),
+                name: "id".to_string(),
+            })
+        );
+        assert_eq!(
+            tree.insert("/users/<n:int>?", "GET", same),
+            Err(RouteError::Conflict {
+                pattern: "/users/<n:int>?".to_string(),
+                existing: "/users".to_string(),
+            })
+        );
+        assert!(tree.insert("/files/<p..>?", "GET", same).is_err());
+        assert!(tree.insert("/files/<p..>", "GET", same).is_ok());
+        assert_eq!(tree.values().len(), 6);
+
+        // An optional catch-all also matches where it starts
+        assert_eq!(
+            tree.insert("/files", "GET", same),
+            Err(RouteError::Conflict {
+                pattern: "/files".to_string(),
+                existing: "/files".to_string(),
+            })
+        );
+        tree.insert("/docs", "GET", same).unwrap();
+        assert_eq!(
+            tree.insert("/docs/<p..>?", "GET", same),
+            Err(RouteError::Conflict {
+                pattern: "/docs/<p..>?".to_string(),
+                existing: "/docs".to_string(),
+            })
+        );
+        assert!(tree.insert_prefix("/docs", "path", "GET", same).is_err());
+        assert!(tree.insert("/docs/<p..>", "GET", same).is_ok());
+        assert!(tree.insert("/docs/<p..>?", "POST", same).is_ok());
+        assert!(tree.insert("/docs", "POST", same).is_err());
+    }
+
+    #[test]
+    fn test_nest() {
+        struct Noop;
+        impl Middleware for Noop {
+            fn handle(&self, ctx: &mut RequestContext, next: Next) -> Response {
+                next.run(ctx)
+            }
+        }
+        let handler = || -> HandlerFunc { Box::new(|_| crate::http::ok()) };
+
+        let mut users = Router::new();
+        users.add_handler(Method::GET, "/", handler()).unwrap();
+        users
+            .add_handler(Method::GET, "/<id:int>", handler())
+            .unwrap();
+        users
+            .add_handler_with(Method::PUT, "/<id:int>", vec![Box::new(Noop)], handler())
+            .unwrap();
+        users.add_middleware(Box::new(Noop));
+
+        let mut api = Router::new();
+        api.nest("/users", users).unwrap();
+        api.mount("/static", handler()).unwrap();
+        api.add_middleware(Box::new(Noop));
+        api.add_middleware(Box::new(Noop));
+
+        let mut root = Router::new();
+        root.add_handler(Method::POST, "/api/v1/users", handler())
+            .unwrap();
+        root.nest("/api/v1/", api).unwrap();
+
+        let layers = |path| -> Vec<Vec<usize>> {
+            root.routes
+                .lookup(path)
+                .iter()
+                .map(|(h, _)| h.layers.iter().map(|l| l.len()).collect())
+                .collect()
+        };
+        // The one registered directly comes first, then the nested one
+        assert_eq!(layers("/api/v1/users"), vec![vec![], vec![2, 1]]);
+        // Route middleware runs last
+        assert_eq!(layers("/api/v1/users/7"), vec![vec![2, 1], vec![2, 1, 1]]);
+        assert_eq!(layers("/api/v1/static/a/b"), vec![vec![2]]);
+        assert!(layers("/users/7").is_empty());
+
+        let mut other = Router::new();
+        other
+            .add_handler(Method::GET, "/<n:int>", handler())
+            .unwrap();
+        for path in ["/a", "/b", "/c"] {
+            other.add_handler(Method::GET, path, handler()).unwrap();
+        }
+        assert_eq!(
+            root.nest("/api/v1/users", other),
+            Err(RouteError::Conflict {
+                pattern: "/api/v1/users/<n:int>".to_string(),
+                existing: "/api/v1/users/<id:int>".to_string(),
+            })
+        );
+        // Nothing was added
+        assert!(root.routes.lookup("/api/v1/users/a").is_empty());
+        assert_eq!(root.routes.values().len(), 5);
+
+        // Both end up at the prefix
+        let mut other = Router::new();
+        other.add_handler(Method::GET, "", handler()).unwrap();
+        other.add_handler(Method::GET, "/", handler()).unwrap();
+        assert!(matches!(
+            root.nest("/other", other),
+            Err(RouteError::Conflict { .. })
+        ));
+        assert_eq!(root.routes.values().len(), 5);
+    }
+
+    #[test]
+    fn test_parse_pattern() {
+        assert!(parse_pattern("/a/<b><c>").is_err());
+        assert!(parse_pattern("/a/<path..>/b").is_err());
+        assert!(parse_pattern("/a/x<path..>").is_err());
+        assert!(parse_pattern("/a/<b:float>").is_err());
+        assert!(parse_pattern("/a/<b:regex(()>").is_err());
+        assert!(parse_pattern("/a/<B>").is_err());
+        assert!(parse_pattern("/a/<b").is_err());
+        assert_eq!(
+            parse_pattern("/<d:regex(\\d{2}(?<x>a|>))>").unwrap()[0].0,
+            Segment::Param(ParamSpec {
+                prefix: String::new(),
+                name: "d".to_string(),
+                constraint: Constraint::parse("regex(\\d{2}(?<x>a|>))").unwrap(),
+                suffix: String::new(),
+            })
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use crate::http::header::HeaderMap;
+use crate::http::status::Status;
+use crate::http::{Response, forbidden, not_found};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PathError {
+    #[error("path escapes the root directory")]
+    Forbidden,
+    #[error("path not found")]
+    NotFound,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<PathError> for Response {
+    fn from(e: PathError) -> Self {
+        match e {
+            PathError::Forbidden => forbidden(),
+            PathError::NotFound => not_found(),
+            PathError::Io(e) if e.kind() == io::ErrorKind::NotFound => not_found(),
+            PathError::Io(_) => {
+                Response::from_parts(Status::INTERNAL_SERVER_ERROR, HeaderMap::new(), None)
+            }
+        }
+    }
+}
+
+/// Resolves user-supplied relative paths against a directory, refusing any
+/// result outside of it.
+#[derive(Debug, Clone)]
+pub struct SafeRoot {
+    root: PathBuf,
+    allow_symlink_escape: bool,
+}
+
+impl SafeRoot {
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            root: root.as_ref().canonicalize()?,
+            allow_symlink_escape: false,
+        })
+    }
+
+    /// Lets symlinks inside the root point anywhere. `..` and absolute paths
+    /// are still refused.
+    pub fn allow_symlink_escape(mut self, allow: bool) -> Self {
+        self.allow_symlink_escape = allow;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolves `rel` to an existing path or, if it doesn't exist, to a path
+    /// in an existing directory, so it can also be used for new files.
+    pub fn resolve(&self, rel: &str) -> Result<PathBuf, PathError> {
+        let mut path = self.root.clone();
+        for component in Path::new(rel).components() {
+            match component {
+                Component::Normal(c) => path.push(c),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    return Err(PathError::Forbidden);
+                }
+            }
+        }
+
+        if self.allow_symlink_escape {
+            return Ok(path);
+        }
+
+        let resolved = match path.canonicalize() {
+            Ok(p) => p,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // A dangling symlink would be followed when the file gets created
+                if path.symlink_metadata().is_ok() {
+                    return Err(PathError::Forbidden);
+                }
+
+                let file_name = path.file_name().ok_or(PathError::Forbidden)?;
+                let parent = path
+                    .parent()
+                    .ok_or(PathError::Forbidden)?
+                    .canonicalize()
+                    .map_err(|e| match e.kind() {
+                        io::ErrorKind::NotFound => PathError::NotFound,
+                        _ => PathError::Io(e),
+                    })?;
+                parent.join(file_name)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if resolved.starts_with(&self.root) {
+            Ok(resolved)
+        } else {
+            Err(PathError::Forbidden)
+        }
+    }
+}
+
+/// A fresh directory under the system's temporary directory for tests, removed
+/// with its contents when dropped.
+#[cfg(test)]
+pub(crate) struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn temp_root(name: &str) -> TempDir {
+        let dir = TempDir::new(&format!("safe-path-{}", name));
+        fs::create_dir_all(dir.join("root/sub")).unwrap();
+        fs::write(dir.join("root/sub/a.txt"), "a").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = temp_root("resolve");
+        let root = SafeRoot::new(dir.join("root")).unwrap();
+
+        assert_eq!(
+            root.resolve("sub/./a.txt").unwrap(),
+            root.root().join("sub/a.txt")
+        );
+        assert_eq!(
+            root.resolve("new.txt").unwrap(),
+            root.root().join("new.txt")
+        );
+        assert!(matches!(
+            root.resolve("../secret.txt"),
+            Err(PathError::Forbidden)
+        ));
+        assert!(matches!(
+            root.resolve("sub/../../secret.txt"),
+            Err(PathError::Forbidden)
+        ));
+        assert!(matches!(
+            root.resolve("/etc/passwd"),
+            Err(PathError::Forbidden)
+        ));
+        assert!(matches!(
+            root.resolve("missing/new.txt"),
+            Err(PathError::NotFound)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_symlinks() {
+        let dir = temp_root("symlinks");
+        std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("root/link")).unwrap();
+        std::os::unix::fs::symlink(dir.join("root/sub/a.txt"), dir.join("root/inner")).unwrap();
+
+        let root = SafeRoot::new(dir.join("root")).unwrap();
+        assert!(matches!(root.resolve("link"), Err(PathError::Forbidden)));
+        assert_eq!(
+            root.resolve("inner").unwrap(),
+            root.root().join("sub/a.txt")
+        );
+
+        std::os::unix::fs::symlink(dir.join("missing.txt"), dir.join("root/dangling")).unwrap();
+        assert!(matches!(
+            root.resolve("dangling"),
+            Err(PathError::Forbidden)
+        ));
+
+        let root = root.allow_symlink_escape(true);
+        assert_eq!(root.resolve("link").unwrap(), root.root().join("link"));
+        assert!(matches!(
+            root.resolve("../secret.txt"),
+            Err(PathError::Forbidden)
+        ));
+    }
+}
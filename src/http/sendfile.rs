@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+
+/// A writer that can send a region of a file itself, for file-backed bodies.
+pub trait FileSink: Write {
+    /// Writes `len` bytes of `file` starting at `offset`. The default copies
+    /// through a buffer.
+    fn write_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+        copy_buffered(file, offset, len, self)
+    }
+}
+
+impl FileSink for Vec<u8> {}
+
+impl FileSink for TcpStream {
+    #[cfg(target_os = "linux")]
+    fn write_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+        sendfile(self, file, offset, len)
+    }
+}
+
+fn copy_buffered<W: Write + ?Sized>(
+    mut file: &File,
+    offset: u64,
+    len: u64,
+    w: &mut W,
+) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    let copied = io::copy(&mut file.take(len), w)?;
+    if copied < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file is shorter than the response body",
+        ));
+    }
+    Ok(())
+}
+
+/// Lets the kernel copy the file to the socket, without going through user space.
+#[cfg(target_os = "linux")]
+fn sendfile(out: &mut TcpStream, file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // Linux transfers at most this much per call
+    const MAX_CHUNK: u64 = 0x7fff_f000;
+
+    let mut off = offset as libc::off_t;
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = remaining.min(MAX_CHUNK) as usize;
+        // SAFETY: both descriptors stay open during the call and `off` outlives it
+        let n = unsafe { libc::sendfile(out.as_raw_fd(), file.as_raw_fd(), &mut off, count) };
+
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // The file system doesn't support it; nothing has been sent yet
+                Some(libc::EINVAL | libc::ENOSYS) if remaining == len => {
+                    return copy_buffered(file, offset, len, out);
+                }
+                _ => return Err(e),
+            }
+        }
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file is shorter than the response body",
+            ));
+        }
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::safe_path::TempDir;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_write_file_short() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let dir = TempDir::new("sendfile-short");
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "hello world").unwrap();
+        let file = File::open(&path).unwrap();
+        let result = stream.write_file(&file, 6, 10);
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,47 +1,50 @@
 use crate::concurrency::ThreadPool;
 use crate::http;
+use crate::http::body::Body;
+use crate::http::chunked::{ChunkedDecoder, ChunkedWriter};
 use crate::http::handler::HandlerFunc;
+use crate::http::header::HeaderMap;
 use crate::http::method::Method;
 use crate::http::middleware::compression::CompressionMw;
 use crate::http::middleware::{Middleware, Next};
-use crate::http::request::{Request, RequestContext};
-use crate::http::{BUFFER_SIZE, Response, bad_request};
-use anyhow::{Context, anyhow, bail};
-use once_cell::sync::Lazy;
-use regex::{Captures, Regex};
-use std::cmp::min;
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read, Write};
+use crate::http::request::{BodyState, Request, RequestBody, RequestContext};
+use crate::http::router::{Handler, RouteError, Router};
+use crate::http::sendfile::FileSink;
+use crate::http::status::Status;
+use crate::http::url::{Query, normalize_path, split_target};
+use crate::http::{BUFFER_SIZE, Response, bad_request, is_token, parse_header_line};
+use anyhow::{Context, bail};
+use std::io::{BufRead, BufReader, Read};
 use std::net::{TcpListener, TcpStream};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
-
-pub struct Handler {
-    method: Method,
-    regex: Regex,
-    pub f: HandlerFunc,
-}
+use thiserror::Error;
 
 pub struct Server {
     listener: TcpListener,
-    handlers: Vec<Handler>,
+    router: Router,
     pool: ThreadPool,
-    middlewares: Vec<Box<dyn Middleware>>,
+    max_buffered_body: usize,
 }
 
-static PATTERN_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"<(?P<var>[a-z][a-z0-9]*)>").unwrap());
+const DEFAULT_MAX_BUFFERED_BODY: usize = 1024 * 1024;
+
+/// A request body with a transfer coding other than `chunked`, answered with 501.
+#[derive(Error, Debug)]
+#[error("unsupported transfer coding {0:?}")]
+struct UnsupportedTransferCoding(String);
 
 impl Server {
     fn new(listener: TcpListener, num_workers: usize) -> Server {
         let mut s = Server {
             listener,
-            handlers: Vec::new(),
+            router: Router::new(),
             pool: ThreadPool::new(num_workers),
-            middlewares: Vec::new(),
+            max_buffered_body: DEFAULT_MAX_BUFFERED_BODY,
         };
 
-        s.add_middleware(Box::new(CompressionMw {}));
+        s.add_middleware(Box::new(CompressionMw::new()));
         s
     }
 
@@ -50,25 +53,53 @@ impl Server {
         Ok(Server::new(listener, num_workers))
     }
 
-    pub fn add_handler(&mut self, m: Method, pattern: &str, f: HandlerFunc) {
-        let pattern = PATTERN_RE
-            .replace_all(pattern, |capt: &Captures| {
-                format!(r"(?<{}>[^/?]+)", capt.name("var").unwrap().as_str())
-            })
-            .to_string();
-        let pattern = format!("^{}$", pattern);
+    /// Registers a handler, see `Router::add_handler`.
+    pub fn add_handler(
+        &mut self,
+        m: Method,
+        pattern: &str,
+        f: HandlerFunc,
+    ) -> Result<(), RouteError> {
+        self.router.add_handler(m, pattern, f)
+    }
+
+    /// Registers a handler with middleware that runs only for it, inside the
+    /// global middleware, see `Router::add_handler_with`.
+    pub fn add_handler_with(
+        &mut self,
+        m: Method,
+        pattern: &str,
+        middlewares: Vec<Box<dyn Middleware>>,
+        f: HandlerFunc,
+    ) -> Result<(), RouteError> {
+        self.router.add_handler_with(m, pattern, middlewares, f)
+    }
 
-        let compiled = Regex::new(&pattern).unwrap();
+    /// Registers a GET handler for `prefix` and everything below it, see `Router::mount`.
+    pub fn mount(&mut self, prefix: &str, f: HandlerFunc) -> Result<(), RouteError> {
+        self.router.mount(prefix, f)
+    }
 
-        self.handlers.push(Handler {
-            method: m,
-            regex: compiled,
-            f,
-        })
+    /// Adds the handlers of `router` below `prefix`, see `Router::nest`.
+    pub fn nest(&mut self, prefix: &str, router: Router) -> Result<(), RouteError> {
+        self.router.nest(prefix, router)
     }
 
+    /// Adds middleware that runs for every handler, in the order it was added.
     pub fn add_middleware(&mut self, m: Box<dyn Middleware>) {
-        self.middlewares.push(m);
+        self.router.add_middleware(m);
+    }
+
+    /// Replaces the response compression installed by default, which runs before
+    /// all other middleware.
+    pub fn set_compression(&mut self, compression: CompressionMw) {
+        self.router.middlewares[0] = Box::new(compression);
+    }
+
+    /// Bodies up to this size are read into `Request::content` before dispatch;
+    /// larger ones are only available through `RequestContext::body`.
+    pub fn set_max_buffered_body(&mut self, size: usize) {
+        self.max_buffered_body = size;
     }
 
     pub fn run(self) -> Result<(), &'static str> {
@@ -83,37 +114,57 @@ impl Server {
         Ok(())
     }
 
-    fn process_incoming(&self, mut stream: TcpStream) {
+    fn process_incoming(&self, stream: TcpStream) {
         stream
             .set_read_timeout(Some(Duration::from_secs(5)))
             .unwrap();
 
         println!("accepted new connection: {:?}", stream.peer_addr());
 
+        let mut rdr = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+
         loop {
-            let mut close = false;
+            let close;
+            let mut head_only = false;
 
-            let request = Self::read_request(&mut stream);
+            let request = Self::read_request(&mut rdr, self.max_buffered_body);
 
             let response = match request {
-                Ok(Some(r)) => {
-                    close = r
+                Ok(Some((r, mut body_state))) => {
+                    let close_requested = r
                         .get_header("Connection")
                         .map(|v| v.eq("close"))
                         .unwrap_or(false);
-                    let mut resp = self.dispatch(&r);
+                    let mut resp =
+                        self.dispatch(&r, RequestBody::new(&r, &mut rdr, &mut body_state));
+
+                    let drained = body_state
+                        .drain(&mut rdr, self.max_buffered_body as u64)
+                        .unwrap_or(false);
+                    close = close_requested || !drained;
+                    head_only = r.method == Method::HEAD;
+
                     if close {
-                        resp.headers.insert("Connection".to_string(), "close".to_string());
+                        resp.headers.insert("Connection", "close");
                     }
                     resp
                 }
                 Ok(None) => break,
-                Err(_) => bad_request(),
+                Err(e) => {
+                    close = true;
+                    if e.is::<UnsupportedTransferCoding>() {
+                        Response::from_parts(Status::NOT_IMPLEMENTED, HeaderMap::new(), None)
+                    } else {
+                        bad_request()
+                    }
+                }
             };
 
-            stream
-                .write_all(serialize_response(&response).as_ref())
-                .unwrap();
+            if let Err(e) = write_response(&mut stream, response, head_only) {
+                println!("error writing response: {}", e);
+                break;
+            }
 
             if close {
                 break;
@@ -121,51 +172,80 @@ impl Server {
         }
     }
 
-    fn dispatch(&self, req: &Request) -> Response {
-        let handler = self
-            .handlers
-            .iter()
-            .map(|h| (h, h.regex.captures(&req.url)))
-            .find(|(h, c)| h.method == req.method && c.is_some());
+    fn dispatch<'a>(&self, req: &'a Request, body: RequestBody<'a>) -> Response {
+        // Handlers of the matching routes in order of precedence
+        let matching = self.router.routes.lookup(&req.path);
+        let find = |method: &Method| matching.iter().find(|(h, _)| h.method == *method);
 
-        if let Some((handler, Some(capt))) = handler {
-            let vars = Self::get_url_vars(handler, capt);
-            let mut req_ctx = RequestContext::from(req, vars);
+        let mut handler = find(&req.method);
+        // HEAD is answered by the GET handler; the body is dropped when writing the response
+        if handler.is_none() && req.method == Method::HEAD {
+            handler = find(&Method::GET);
+        }
+
+        if let Some((handler, vars)) = handler {
+            let vars = vars.iter().cloned().collect();
+            let mut req_ctx = RequestContext::from(req, vars, body);
 
             let next = Next {
-                middlewares: self.middlewares.as_ref(),
+                middlewares: self.router.middlewares.as_ref(),
+                layers: &handler.layers,
                 handler: &handler.f,
             };
 
             next.run(&mut req_ctx)
+        } else if req.method == Method::OPTIONS && req.url == "*" {
+            let allow = Self::allowed_methods(self.router.routes.values().into_iter());
+            Response::from_parts(Status::NO_CONTENT, HeaderMap::from([allow]), None)
+        } else if req.method == Method::OPTIONS && !matching.is_empty() {
+            let allow = Self::allowed_methods(matching.iter().map(|(h, _)| *h));
+            Response::from_parts(Status::NO_CONTENT, HeaderMap::from([allow]), None)
+        } else if !matching.is_empty() {
+            let allow = Self::allowed_methods(matching.iter().map(|(h, _)| *h));
+            Response::from_parts(Status::METHOD_NOT_ALLOWED, HeaderMap::from([allow]), None)
         } else {
             http::not_found()
         }
     }
 
-    fn get_url_vars(handler: &Handler, capt: Captures) -> HashMap<String, String> {
-        handler
-            .regex
-            .capture_names()
-            .flatten()
-            .map(|name| {
-                (
-                    name.to_string(),
-                    capt.name(name).unwrap().as_str().to_string(),
-                )
-            })
-            .collect()
+    /// Builds the `Allow` header, including the implicitly handled HEAD and OPTIONS.
+    fn allowed_methods<'h>(handlers: impl Iterator<Item = &'h Handler>) -> (String, String) {
+        let mut methods: Vec<&Method> = Vec::new();
+        for h in handlers {
+            if !methods.contains(&&h.method) {
+                methods.push(&h.method);
+            }
+        }
+        if methods.contains(&&Method::GET) && !methods.contains(&&Method::HEAD) {
+            methods.push(&Method::HEAD);
+        }
+        if !methods.contains(&&Method::OPTIONS) {
+            methods.push(&Method::OPTIONS);
+        }
+
+        let allow = methods
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        ("Allow".to_string(), allow)
     }
 
-    fn read_request(readable: &mut impl Read) -> anyhow::Result<Option<Request>> {
-        let mut rdr = BufReader::new(readable);
+    fn read_request(
+        rdr: &mut impl BufRead,
+        max_buffered_body: usize,
+    ) -> anyhow::Result<Option<(Request, BodyState)>> {
         let mut line_buf = String::with_capacity(64);
 
         // Skip empty lines
         loop {
-            let n_read = rdr
-                .read_line(&mut line_buf)
-                .context("Error while reading line")?;
+            line_buf.clear();
+            let n_read = match rdr.read_line(&mut line_buf) {
+                Ok(n) => n,
+                // Idle keep-alive connection timed out
+                Err(e) if line_buf.is_empty() && is_timeout(&e) => return Ok(None),
+                Err(e) => return Err(e).context("Error while reading line"),
+            };
             if n_read == 0 {
                 return Ok(None);
             }
@@ -180,23 +260,35 @@ impl Server {
 
         let method;
         let url;
+        let path;
+        let query;
 
         match first_line_parts[..] {
             [method_raw, target, version] => {
+                if !is_token(method_raw) {
+                    bail!("Invalid HTTP method");
+                }
                 method = Method::from_str(method_raw).context("Unknown HTTP method")?;
 
                 if !version.eq("HTTP/1.1") {
                     bail!("Unsupported HTTP version");
                 }
 
+                // They could end up in headers built from the target, like `Location`
+                if target.bytes().any(|b| b.is_ascii_control()) {
+                    bail!("Invalid request target");
+                }
                 url = String::from(target);
+                let (target_path, target_query) = split_target(target);
+                path = normalize_path(target_path)?;
+                query = target_query.map(Query::parse).unwrap_or_default();
             }
             _ => {
                 bail!("Bad start-line");
             }
         }
 
-        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut headers = HeaderMap::new();
 
         loop {
             line_buf.clear();
@@ -206,58 +298,114 @@ impl Server {
                 break;
             }
 
-            let (k, v) = line_buf
-                .trim_ascii()
-                .split_once(":")
-                .ok_or(anyhow!("Invalid header"))?;
-            headers.insert(k.trim_ascii().to_lowercase(), String::from(v.trim_ascii()));
+            let (k, v) = parse_header_line(&line_buf)?;
+            headers.try_append(k, v)?;
         }
 
-        let content = if let Some(content_length_raw) = headers.get("content-length") {
-            let content_length: usize =
-                content_length_raw.parse().context("Invalid header value")?;
-            Self::read_content(&mut rdr, content_length)?
-        } else {
-            Vec::default()
-        };
+        let mut trailers = HeaderMap::new();
+        let mut content = Vec::new();
+        let mut body_state = BodyState::default();
+
+        let transfer_coding: Vec<&str> = headers
+            .get_all("transfer-encoding")
+            .flat_map(|v| v.split(','))
+            .map(str::trim_ascii)
+            .filter(|c| !c.is_empty())
+            .collect();
+        let content_length = Self::content_length(&headers)?;
+
+        if !transfer_coding.is_empty() {
+            // Intermediaries could frame such a message differently (RFC 9112, section 6.1)
+            if content_length.is_some() {
+                bail!("Both Transfer-Encoding and Content-Length");
+            }
+            // Other codings would reach the handler still applied to the body
+            if !matches!(transfer_coding[..], [c] if c.eq_ignore_ascii_case("chunked")) {
+                return Err(UnsupportedTransferCoding(transfer_coding.join(", ")).into());
+            }
+
+            let mut decoder = ChunkedDecoder::default();
+            let mut buf = [0u8; BUFFER_SIZE];
+            while !decoder.is_done() && content.len() <= max_buffered_body {
+                let n = decoder
+                    .read(rdr, &mut buf)
+                    .context("Error while reading chunked body")?;
+                content.extend_from_slice(&buf[..n]);
+            }
+
+            if decoder.is_done() {
+                trailers = decoder.trailers().clone();
+            } else {
+                body_state = BodyState::chunked(std::mem::take(&mut content), decoder);
+            }
+        } else if let Some(content_length) = content_length {
+            if content_length <= max_buffered_body as u64 {
+                content = Self::read_content(rdr, content_length)?;
+            } else {
+                body_state = BodyState::with_length(content_length);
+            }
+        }
 
         let request = Request {
             method,
             url,
+            path,
+            query,
             headers,
+            trailers,
             content,
         };
-        Ok(Some(request))
-    }
-
-    fn read_content(
-        rdr: &mut BufReader<&mut impl Read>,
-        mut content_length: usize,
-    ) -> anyhow::Result<Vec<u8>> {
-        let mut content = Vec::with_capacity(content_length);
-        while content_length > 0 {
-            let mut buf: [u8; BUFFER_SIZE] = [0u8; BUFFER_SIZE];
-            let slice_to_read = &mut buf[..min(BUFFER_SIZE, content_length)];
-
-            let bytes_read = rdr
-                .read(slice_to_read)
-                .context("Error while reading content")?;
-            if bytes_read == 0 {
-                break;
-            } else {
-                content.extend_from_slice(slice_to_read);
-                content_length -= bytes_read;
+        Ok(Some((request, body_state)))
+    }
+
+    /// A list of identical values is allowed, as sent by some intermediaries
+    /// (RFC 9112, section 6.3).
+    fn content_length(headers: &HeaderMap) -> anyhow::Result<Option<u64>> {
+        let mut length = None;
+        for value in headers.get_all("content-length").flat_map(|v| v.split(',')) {
+            let value = value.trim_ascii();
+            // `u64::from_str` would also accept a sign
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                bail!("Invalid Content-Length");
             }
+            let n: u64 = value.parse().context("Invalid Content-Length")?;
+            if length.is_some_and(|l| l != n) {
+                bail!("Conflicting Content-Length values");
+            }
+            length = Some(n);
+        }
+        Ok(length)
+    }
+
+    fn read_content(rdr: &mut impl BufRead, content_length: u64) -> anyhow::Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(content_length as usize);
+        rdr.take(content_length)
+            .read_to_end(&mut content)
+            .context("Error while reading content")?;
+        if content.len() as u64 != content_length {
+            bail!("Unexpected end of content");
         }
         Ok(content)
     }
 }
 
-pub fn serialize_response(response: &Response) -> Vec<u8> {
-    let content_len = response.content.as_ref().map(|c| c.len()).unwrap_or(0);
-    let mut resp_bytes = Vec::with_capacity(content_len + response.headers.len() * 32);
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// Writes the response; with `head_only` (a HEAD request) the body is omitted but the
+/// header section stays the same as for GET.
+pub fn write_response(
+    w: &mut impl FileSink,
+    response: Response,
+    head_only: bool,
+) -> std::io::Result<()> {
+    let mut head = Vec::with_capacity(64 + response.headers.len() * 32);
 
-    resp_bytes.extend(
+    head.extend(
         format!(
             "HTTP/1.1 {} {}\r\n",
             response.status.code_num, response.status.message
@@ -265,17 +413,348 @@ pub fn serialize_response(response: &Response) -> Vec<u8> {
         .as_bytes(),
     );
 
-    for (key, value) in &response.headers {
-        resp_bytes.extend(format!("{}: {}\r\n", key, value).as_bytes());
+    for (key, value) in response.headers.iter() {
+        head.extend(format!("{}: {}\r\n", key, value).as_bytes());
     }
 
-    if let Some(c) = &response.content {
-        resp_bytes.extend(format!("Content-Length: {}\r\n", c.len()).as_bytes());
-        resp_bytes.extend("\r\n".as_bytes());
-        resp_bytes.extend(c);
-    } else {
-        resp_bytes.extend("\r\n".as_bytes());
+    let has_content_length = response.headers.contains("Content-Length");
+
+    match response.content {
+        Body::Empty => {
+            // 1xx, 204 and 304 responses never have content
+            let code = response.status.code_num;
+            if !has_content_length && code >= 200 && code != 204 && code != 304 {
+                head.extend("Content-Length: 0\r\n".as_bytes());
+            }
+            head.extend("\r\n".as_bytes());
+            w.write_all(&head)?;
+        }
+        Body::Bytes(c) => {
+            if !has_content_length {
+                head.extend(format!("Content-Length: {}\r\n", c.len()).as_bytes());
+            }
+            head.extend("\r\n".as_bytes());
+            if !head_only {
+                head.extend(c);
+            }
+            w.write_all(&head)?;
+        }
+        Body::File(f) => {
+            if !has_content_length {
+                head.extend(format!("Content-Length: {}\r\n", f.len()).as_bytes());
+            }
+            head.extend("\r\n".as_bytes());
+            w.write_all(&head)?;
+            if !head_only {
+                f.write_to(w)?;
+            }
+        }
+        Body::Stream(_) if head_only => {
+            head.extend("Transfer-Encoding: chunked\r\n".as_bytes());
+            head.extend("\r\n".as_bytes());
+            w.write_all(&head)?;
+        }
+        Body::Stream(f) => {
+            head.extend("Transfer-Encoding: chunked\r\n".as_bytes());
+            head.extend("\r\n".as_bytes());
+            w.write_all(&head)?;
+
+            let mut chunked = ChunkedWriter::new(&mut *w);
+            let trailers = f(&mut chunked)?;
+            chunked.finish(&trailers)?;
+        }
+    }
+
+    w.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::body::FileBody;
+    use crate::http::middleware::compression::NoCompressionMw;
+    use crate::http::safe_path::TempDir;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn test_write_streamed_response() {
+        let body = Body::stream(|w| {
+            w.write_all(b"hello ")?;
+            w.write_all(b"world")?;
+            Ok(HeaderMap::from([("x-done", "1")]))
+        });
+        let resp = Response::from_parts(Status::OK, HeaderMap::new(), body);
+
+        let mut out = Vec::new();
+        write_response(&mut out, resp, false).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+             6\r\nhello \r\n5\r\nworld\r\n0\r\nx-done: 1\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_write_file_response() {
+        let dir = TempDir::new("file-body");
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "hello world").unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+
+        let body = FileBody::with_range(file, 6, 5);
+        let resp = Response::from_parts(Status::OK, HeaderMap::new(), body);
+
+        let mut out = Vec::new();
+        write_response(&mut out, resp, false).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nworld"
+        );
+    }
+
+    #[test]
+    fn test_write_file_response_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let reader = thread::spawn(move || {
+            let mut out = Vec::new();
+            (&client).read_to_end(&mut out).unwrap();
+            out
+        });
+
+        let content: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let dir = TempDir::new("file-body-tcp");
+        let path = dir.join("a.bin");
+        std::fs::write(&path, &content).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+
+        // Sent by the kernel, which has to start at the offset and stop at the length
+        let body = FileBody::with_range(file, 1000, 200_000);
+        let resp = Response::from_parts(Status::OK, HeaderMap::new(), body);
+        write_response(&mut stream, resp, false).unwrap();
+        drop(stream);
+
+        let out = reader.join().unwrap();
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 200000\r\n\r\n";
+        assert_eq!(&out[..head.len()], head);
+        assert!(out[head.len()..] == content[1000..201_000]);
+    }
+
+    #[test]
+    fn test_read_request_large_body() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello worldGET";
+        let mut rdr = raw.as_bytes();
+        let (req, mut state) = Server::read_request(&mut rdr, 4).unwrap().unwrap();
+        // Over the limit the body is left on the connection until it's read
+        assert!(req.content.is_empty());
+        assert_eq!(rdr, b"hello worldGET");
+
+        let mut content = String::new();
+        RequestBody::new(&req, &mut rdr, &mut state)
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "hello world");
+        assert_eq!(rdr, b"GET");
+    }
+
+    #[test]
+    fn test_read_request_large_chunked_body() {
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   4\r\nWiki\r\n5\r\npedia\r\n6\r\n in th\r\n0\r\n\r\nGET";
+        let mut rdr = raw.as_bytes();
+        let (req, mut state) = Server::read_request(&mut rdr, 4).unwrap().unwrap();
+        assert!(req.content.is_empty());
+
+        let mut content = String::new();
+        RequestBody::new(&req, &mut rdr, &mut state)
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "Wikipedia in th");
+        assert_eq!(rdr, b"GET");
+    }
+
+    #[test]
+    fn test_read_request_target() {
+        let read = |target: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            Server::read_request(&mut raw.as_bytes(), DEFAULT_MAX_BUFFERED_BODY)
+        };
+
+        let (req, _) = read("/files/docs?a=%0Db").unwrap().unwrap();
+        assert_eq!(req.query.raw(), "a=%0Db");
+
+        for target in ["/files/docs?a=\rb", "/a\tb", "/a\x00", "/a?b=\x7f"] {
+            assert!(read(target).is_err(), "{:?}", target);
+        }
     }
 
-    resp_bytes
+    #[test]
+    fn test_read_request_framing() {
+        let read = |head: &str| {
+            let raw = format!("POST / HTTP/1.1\r\n{}\r\n3\r\nabc\r\n0\r\n\r\n", head);
+            Server::read_request(&mut raw.as_bytes(), DEFAULT_MAX_BUFFERED_BODY)
+        };
+
+        let (req, _) = read("Transfer-Encoding: chunked\r\n").unwrap().unwrap();
+        assert_eq!(req.content, b"abc");
+        let (req, _) = read("Content-Length: 3, 3\r\n").unwrap().unwrap();
+        assert_eq!(req.content, b"3\r\n");
+
+        let err = read("Transfer-Encoding: gzip, chunked\r\n").unwrap_err();
+        assert!(err.is::<UnsupportedTransferCoding>());
+        let err = read("Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n").unwrap_err();
+        assert!(err.is::<UnsupportedTransferCoding>());
+
+        let invalid = [
+            "Transfer-Encoding: chunked\r\nContent-Length: 3\r\n",
+            "Content-Length: 3\r\nContent-Length: 50\r\n",
+            "Content-Length: 3, 50\r\n",
+            "Content-Length: +3\r\n",
+            "Content-Length: \r\n",
+        ];
+        for head in invalid {
+            assert!(read(head).is_err(), "{:?}", head);
+        }
+    }
+
+    fn test_server() -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut server = Server::new(listener, 1);
+        server
+            .add_handler(Method::GET, "/a", Box::new(|_| http::ok()))
+            .unwrap();
+        server
+            .add_handler(Method::POST, "/a", Box::new(|_| http::ok()))
+            .unwrap();
+        server
+            .add_handler(Method::DELETE, "/b", Box::new(|_| http::ok()))
+            .unwrap();
+        server
+    }
+
+    /// Parses `raw`, dispatches it and returns the response as written to the connection.
+    fn send(server: &Server, raw: &str) -> String {
+        let mut rdr = raw.as_bytes();
+        let (req, mut state) = Server::read_request(&mut rdr, DEFAULT_MAX_BUFFERED_BODY)
+            .unwrap()
+            .unwrap();
+        let resp = server.dispatch(&req, RequestBody::new(&req, &mut rdr, &mut state));
+
+        let mut out = Vec::new();
+        write_response(&mut out, resp, req.method == Method::HEAD).unwrap();
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let server = test_server();
+
+        let resp = send(&server, "GET /a HTTP/1.1\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let resp = send(&server, "GET /c HTTP/1.1\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_dispatch_head() {
+        let server = test_server();
+
+        let resp = send(&server, "HEAD /a HTTP/1.1\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(resp.ends_with("\r\n\r\n"));
+
+        let resp = send(&server, "HEAD /b HTTP/1.1\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+
+    #[test]
+    fn test_dispatch_options() {
+        let server = test_server();
+
+        let resp = send(&server, "OPTIONS /a HTTP/1.1\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(resp.contains("\r\nAllow: GET, POST, HEAD, OPTIONS\r\n"));
+
+        let resp = send(&server, "OPTIONS * HTTP/1.1\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 204 No Content\r\n"));
+        // The order of methods from different routes isn't specified
+        let allow = resp
+            .lines()
+            .find_map(|l| l.strip_prefix("Allow: "))
+            .unwrap();
+        let mut methods: Vec<&str> = allow.split(", ").collect();
+        methods.sort();
+        assert_eq!(methods, ["DELETE", "GET", "HEAD", "OPTIONS", "POST"]);
+
+        let resp = send(&server, "OPTIONS /c HTTP/1.1\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_dispatch_method_not_allowed() {
+        let server = test_server();
+
+        let resp = send(&server, "PUT /a HTTP/1.1\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(resp.contains("\r\nAllow: GET, POST, HEAD, OPTIONS\r\n"));
+
+        let resp = send(&server, "GET /b HTTP/1.1\r\n\r\n");
+        assert!(resp.contains("\r\nAllow: DELETE, OPTIONS\r\n"));
+    }
+    #[test]
+    fn test_dispatch_nested() {
+        struct Record(&'static str, Arc<Mutex<Vec<&'static str>>>);
+        impl Middleware for Record {
+            fn handle(&self, ctx: &mut RequestContext, next: Next) -> Response {
+                self.1.lock().unwrap().push(self.0);
+                next.run(ctx)
+            }
+        }
+        let text: fn(&RequestContext) -> Response = |_| {
+            let headers = HeaderMap::from([("Content-Type", "text/plain")]);
+            Response::from_parts(Status::OK, headers, vec![b'a'; 1000])
+        };
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let record = |name| Box::new(Record(name, Arc::clone(&calls)));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut server = Server::new(listener, 1);
+        server.add_middleware(record("server"));
+
+        let mut api = Router::new();
+        api.add_middleware(record("router"));
+        api.add_handler_with(Method::GET, "/a", vec![record("route")], Box::new(text))
+            .unwrap();
+        api.add_handler_with(
+            Method::GET,
+            "/b",
+            vec![Box::new(NoCompressionMw)],
+            Box::new(text),
+        )
+        .unwrap();
+        server.nest("/api", api).unwrap();
+
+        // Route middleware runs inside the router's, inside the server's
+        let resp = send(
+            &server,
+            "GET /api/a HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+        );
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(resp.contains("\r\nContent-Encoding: gzip\r\n"));
+        assert_eq!(*calls.lock().unwrap(), ["server", "router", "route"]);
+
+        // The global compression leaves the route's response alone
+        let resp = send(
+            &server,
+            "GET /api/b HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+        );
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!resp.contains("Content-Encoding"));
+        assert!(resp.contains("\r\nContent-Length: 1000\r\n"));
+    }
 }
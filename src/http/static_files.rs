@@ -0,0 +1,642 @@
+use crate::http::body::{Body, FileBody};
+use crate::http::encoding::{AcceptEncoding, Encoding};
+use crate::http::etag::EntityTag;
+use crate::http::header::HeaderMap;
+use crate::http::method::Method;
+use crate::http::middleware::conditional::{
+    Validators, evaluate_preconditions, precondition_response,
+};
+use crate::http::range::{RangeRequest, content_range, parse_range};
+use crate::http::request::RequestContext;
+use crate::http::safe_path::SafeRoot;
+use crate::http::status::Status;
+use crate::http::{Response, bad_request, not_found};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use serde::Serialize;
+use std::fs;
+use std::fs::{File, Metadata};
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Characters escaped in links of a directory listing.
+const LINK_ESCAPE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Extensions of precompressed files, in order of preference.
+const PRECOMPRESSED: [(Encoding, &str); 3] = [
+    (Encoding::Br, "br"),
+    (Encoding::Zstd, "zst"),
+    (Encoding::Gzip, "gz"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DirListing {
+    Disabled,
+    Html,
+    Json,
+}
+
+/// Serves files from a directory; register it with `Server::mount`.
+pub struct StaticFiles {
+    root: SafeRoot,
+    index_file: Option<String>,
+    listing: DirListing,
+    cache_control: Option<String>,
+    precompressed: bool,
+}
+
+#[derive(Serialize)]
+struct ListingEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    size: u64,
+    modified: Option<String>,
+}
+
+impl StaticFiles {
+    pub fn new(root: SafeRoot) -> Self {
+        Self {
+            root,
+            index_file: Some("index.html".to_string()),
+            listing: DirListing::Disabled,
+            cache_control: None,
+            precompressed: true,
+        }
+    }
+
+    /// File served for a directory request, `index.html` by default.
+    pub fn index_file(mut self, name: Option<&str>) -> Self {
+        self.index_file = name.map(String::from);
+        self
+    }
+
+    /// What to respond for a directory without an index file.
+    pub fn listing(mut self, listing: DirListing) -> Self {
+        self.listing = listing;
+        self
+    }
+
+    /// Value of the `Cache-Control` header sent with files.
+    pub fn cache_control(mut self, value: &str) -> Self {
+        self.cache_control = Some(value.to_string());
+        self
+    }
+
+    /// Serve `file.br`, `file.zst` or `file.gz` instead of `file` to clients that
+    /// accept the encoding. On by default.
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    pub fn handle(&self, ctx: &RequestContext) -> Response {
+        let rel = ctx.get_var("path").unwrap_or("");
+        let rel = rel.trim_start_matches('/');
+
+        let path = match self.root.resolve(rel) {
+            Ok(p) => p,
+            Err(e) => return e.into(),
+        };
+        let metadata = match fs::metadata(&path) {
+            Ok(m) => m,
+            Err(_) => return not_found(),
+        };
+
+        if !metadata.is_dir() {
+            return self.serve_file(ctx, &path, &metadata);
+        }
+
+        // Relative links in the directory's page only work with a trailing slash
+        let req_path = &ctx.request().path;
+        if !req_path.ends_with('/') {
+            let mut location = format!("{}/", req_path);
+            if !ctx.query().raw().is_empty() {
+                location = format!("{}?{}", location, ctx.query().raw());
+            }
+            // The query is copied as it was sent
+            let mut headers = HeaderMap::new();
+            if headers.try_insert("Location", location).is_err() {
+                return bad_request();
+            }
+            return Response::from_parts(Status::MOVED_PERMANENTLY, headers, None);
+        }
+
+        if let Some(index_file) = &self.index_file {
+            let index_path = path.join(index_file);
+            if let Ok(index_metadata) = fs::metadata(&index_path) {
+                if index_metadata.is_file() {
+                    return self.serve_file(ctx, &index_path, &index_metadata);
+                }
+            }
+        }
+
+        match self.listing {
+            DirListing::Disabled => not_found(),
+            DirListing::Html => Self::html_listing(&path, req_path),
+            DirListing::Json => Self::json_listing(&path),
+        }
+    }
+
+    fn serve_file(&self, ctx: &RequestContext, path: &Path, metadata: &Metadata) -> Response {
+        let content_type = content_type(path);
+
+        let variant = self.precompressed_variant(ctx, path);
+        let (path, metadata, encoding) = match &variant {
+            Some((p, m, e)) => (p.as_path(), m, Some(*e)),
+            None => (path, metadata, None),
+        };
+        let len = metadata.len();
+        let validators = Validators::from_metadata(metadata);
+
+        let mut headers = HeaderMap::from([("Content-Type", content_type.as_str())]);
+        if let Some(encoding) = encoding {
+            headers.insert("Content-Encoding", encoding.to_string());
+        }
+        if self.precompressed {
+            headers.insert("Vary", "Accept-Encoding");
+        }
+        headers.insert("Accept-Ranges", "bytes");
+        if let Some(etag) = &validators.etag {
+            headers.insert("ETag", etag.to_string());
+        }
+        if let Some(modified) = validators.last_modified {
+            headers.insert("Last-Modified", httpdate::fmt_http_date(modified));
+        }
+        if let Some(cache_control) = &self.cache_control {
+            headers.insert("Cache-Control", cache_control.as_str());
+        }
+
+        // Checked before reading the file, which a 304 doesn't need
+        let precondition = evaluate_preconditions(ctx, Some(&validators));
+        if let Some(resp) = precondition_response(precondition, &headers) {
+            if resp.status.code_num != Status::NOT_MODIFIED.code_num {
+                return resp;
+            }
+            // The coding of a precompressed file was negotiated here
+            if encoding.is_some() {
+                return resp.without_compression();
+            }
+            return match File::open(path).and_then(FileBody::new) {
+                Ok(file) => resp.replacing(Response::from_parts(Status::OK, headers, file)),
+                Err(_) => not_found(),
+            };
+        }
+
+        // Range is only defined for GET; HEAD gets the headers of the full response
+        let range = match ctx.get_header("Range") {
+            Some(r)
+                if ctx.request().method == Method::GET && if_range_matches(ctx, &validators) =>
+            {
+                parse_range(r, len)
+            }
+            _ => RangeRequest::Full,
+        };
+
+        let result = match range {
+            RangeRequest::Full => File::open(path)
+                .and_then(FileBody::new)
+                .map(|file| Response::from_parts(Status::OK, headers, file)),
+            RangeRequest::Unsatisfiable => {
+                let headers = HeaderMap::from([
+                    ("Accept-Ranges", "bytes".to_string()),
+                    ("Content-Range", format!("bytes */{}", len)),
+                ]);
+                Ok(Response::from_parts(
+                    Status::RANGE_NOT_SATISFIABLE,
+                    headers,
+                    None,
+                ))
+            }
+            RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+                headers.insert("Content-Range", content_range(&ranges[0], len));
+                let range = &ranges[0];
+                File::open(path)
+                    .map(|file| {
+                        FileBody::with_range(file, *range.start(), range.end() - range.start() + 1)
+                    })
+                    .map(|file| Response::from_parts(Status::PARTIAL_CONTENT, headers, file))
+            }
+            RangeRequest::Partial(ranges) => {
+                let boundary = multipart_boundary();
+                headers.insert(
+                    "Content-Type",
+                    format!("multipart/byteranges; boundary={}", boundary),
+                );
+                File::open(path)
+                    .map(|file| multipart_byteranges(file, ranges, len, content_type, boundary))
+                    .map(|content| Response::from_parts(Status::PARTIAL_CONTENT, headers, content))
+            }
+        };
+
+        result.unwrap_or_else(|_| not_found())
+    }
+
+    /// The precompressed file next to `path` with the encoding the client prefers.
+    fn precompressed_variant(
+        &self,
+        ctx: &RequestContext,
+        path: &Path,
+    ) -> Option<(PathBuf, Metadata, Encoding)> {
+        if !self.precompressed {
+            return None;
+        }
+        let accepted = AcceptEncoding::parse(ctx.get_header("Accept-Encoding")?).ok()?;
+        let rel = path.strip_prefix(self.root.root()).ok()?.to_str()?;
+
+        let mut best: Option<(f32, PathBuf, Metadata, Encoding)> = None;
+        for (encoding, ext) in PRECOMPRESSED {
+            let quality = accepted.quality(encoding).unwrap_or(0.0);
+            if quality <= 0.0 || best.as_ref().is_some_and(|b| b.0 >= quality) {
+                continue;
+            }
+
+            // Resolved like the request path, so a symlinked sidecar can't escape the root
+            let Ok(variant) = self.root.resolve(&format!("{}.{}", rel, ext)) else {
+                continue;
+            };
+            if let Ok(metadata) = fs::metadata(&variant) {
+                if metadata.is_file() {
+                    best = Some((quality, variant, metadata, encoding));
+                }
+            }
+        }
+        // The client would rather have the file as it is
+        let (quality, variant, metadata, encoding) = best?;
+        if accepted
+            .quality(Encoding::Identity)
+            .is_some_and(|q| q > quality)
+        {
+            return None;
+        }
+        Some((variant, metadata, encoding))
+    }
+
+    fn list_dir(path: &Path) -> std::io::Result<Vec<ListingEntry>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = match fs::metadata(entry.path()) {
+                Ok(m) => m,
+                // Dangling symlinks
+                Err(_) => continue,
+            };
+
+            entries.push(ListingEntry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                kind: if metadata.is_dir() { "dir" } else { "file" },
+                size: metadata.len(),
+                modified: metadata.modified().ok().map(httpdate::fmt_http_date),
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn html_listing(path: &Path, req_path: &str) -> Response {
+        let entries = match Self::list_dir(path) {
+            Ok(e) => e,
+            Err(_) => return not_found(),
+        };
+
+        let title = format!("Index of {}", html_escape(&decode_path(req_path)));
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+             <body>\n<h1>{0}</h1>\n<ul>\n<li><a href=\"../\">../</a></li>\n",
+            title
+        );
+        for entry in entries {
+            let suffix = if entry.kind == "dir" { "/" } else { "" };
+            html.push_str(&format!(
+                "<li><a href=\"{}{}\">{}{}</a></li>\n",
+                utf8_percent_encode(&entry.name, LINK_ESCAPE),
+                suffix,
+                html_escape(&entry.name),
+                suffix
+            ));
+        }
+        html.push_str("</ul>\n</body>\n</html>\n");
+
+        Response::from_parts(
+            Status::OK,
+            HeaderMap::from([("Content-Type", "text/html; charset=utf-8")]),
+            html.into_bytes(),
+        )
+    }
+
+    fn json_listing(path: &Path) -> Response {
+        let entries = match Self::list_dir(path) {
+            Ok(e) => e,
+            Err(_) => return not_found(),
+        };
+
+        Response::from_parts(
+            Status::OK,
+            HeaderMap::from([("Content-Type", "application/json")]),
+            serde_json::to_vec(&entries).unwrap(),
+        )
+    }
+}
+
+/// A `Range` only applies if the `If-Range` validator still matches (RFC 9110, section 13.1.5).
+fn if_range_matches(ctx: &RequestContext, validators: &Validators) -> bool {
+    let Some(if_range) = ctx.get_header("If-Range") else {
+        return true;
+    };
+
+    // Entity tags need a strong comparison
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return match (EntityTag::parse(if_range), &validators.etag) {
+            (Some(tag), Some(etag)) => tag.strong_eq(etag),
+            _ => false,
+        };
+    }
+
+    match (
+        httpdate::parse_http_date(if_range),
+        validators.last_modified,
+    ) {
+        (Ok(date), Some(modified)) => {
+            httpdate::fmt_http_date(date) == httpdate::fmt_http_date(modified)
+        }
+        _ => false,
+    }
+}
+
+/// Streams the ranges of `file` as a `multipart/byteranges` body, copying
+/// one part at a time.
+fn multipart_byteranges(
+    mut file: File,
+    ranges: Vec<RangeInclusive<u64>>,
+    len: u64,
+    content_type: String,
+    boundary: String,
+) -> Body {
+    Body::stream(move |w| {
+        for range in &ranges {
+            write!(
+                w,
+                "--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                content_type,
+                content_range(range, len)
+            )?;
+            file.seek(SeekFrom::Start(*range.start()))?;
+            let part_len = range.end() - range.start() + 1;
+            // A file that shrank since would leave the part short
+            if io::copy(&mut (&mut file).take(part_len), w)? < part_len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            w.write_all(b"\r\n")?;
+        }
+        write!(w, "--{}--\r\n", boundary)?;
+        Ok(HeaderMap::new())
+    })
+}
+
+fn multipart_boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}{:08x}", nanos.wrapping_mul(0x9e3779b97f4a7c15), n)
+}
+
+/// MIME type by file extension; text types are assumed to be UTF-8.
+pub fn content_type(path: &Path) -> String {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    if mime.type_() == mime_guess::mime::TEXT || mime == mime_guess::mime::APPLICATION_JAVASCRIPT {
+        format!("{}; charset=utf-8", mime.essence_str())
+    } else {
+        mime.essence_str().to_string()
+    }
+}
+
+fn decode_path(path: &str) -> String {
+    percent_encoding::percent_decode_str(path)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::request::{BodyState, Request, RequestBody};
+    use crate::http::safe_path::TempDir;
+    use crate::http::server::write_response;
+    use std::collections::HashMap;
+
+    /// The files under test, and the directory holding them until it's dropped.
+    fn temp_root(name: &str) -> (TempDir, SafeRoot) {
+        let dir = TempDir::new(&format!("static-files-{}", name));
+        fs::write(dir.join("a.txt"), "plain").unwrap();
+        fs::write(dir.join("a.txt.gz"), "gzipped").unwrap();
+        fs::write(dir.join("a.txt.br"), "brotli").unwrap();
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/index.html"), "<p>docs</p>").unwrap();
+        fs::create_dir_all(dir.join("list/sub")).unwrap();
+        fs::write(dir.join("list/b c.txt"), "b").unwrap();
+        let root = SafeRoot::new(dir.to_path_buf()).unwrap();
+        (dir, root)
+    }
+
+    /// Serves `target` as if `files` were mounted at `/files`.
+    fn get(files: &StaticFiles, target: &str, headers: &[(&str, &str)]) -> Response {
+        let request = Request::for_test(Method::GET, target, headers);
+        let rel = request.path.strip_prefix("/files").unwrap().to_string();
+        let mut rdr: &[u8] = &[];
+        let mut state = BodyState::default();
+        let body = RequestBody::new(&request, &mut rdr, &mut state);
+        let ctx = RequestContext::from(&request, HashMap::from([("path".to_string(), rel)]), body);
+        files.handle(&ctx)
+    }
+
+    fn content(resp: Response) -> String {
+        let mut out = Vec::new();
+        write_response(&mut out, resp, false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[test]
+    fn test_precompressed() {
+        let (_dir, root) = temp_root("precompressed");
+        let files = StaticFiles::new(root);
+
+        let cases = [
+            ("", "plain", None),
+            ("gzip", "gzipped", Some("gzip")),
+            ("gzip, br", "brotli", Some("br")),
+            ("gzip, br;q=0.5", "gzipped", Some("gzip")),
+            ("*", "brotli", Some("br")),
+            ("zstd", "plain", None),
+            // Identity is preferred over the best sidecar
+            ("gzip;q=0.5, identity", "plain", None),
+            ("gzip, identity;q=0.5", "gzipped", Some("gzip")),
+        ];
+        for (accept, expected, encoding) in cases {
+            let resp = get(&files, "/files/a.txt", &[("Accept-Encoding", accept)]);
+            assert_eq!(resp.status.code_num, 200, "{}", accept);
+            assert_eq!(resp.headers.get("Vary"), Some("Accept-Encoding"));
+            assert_eq!(resp.headers.get("Content-Encoding"), encoding, "{}", accept);
+            assert_eq!(
+                resp.headers.get("Content-Type"),
+                Some("text/plain; charset=utf-8")
+            );
+            assert_eq!(content(resp), expected, "{}", accept);
+        }
+
+        // The ETag of a sidecar is kept as it is, not tagged by compression
+        let resp = get(&files, "/files/a.txt", &[("Accept-Encoding", "br")]);
+        let etag = resp.headers.get("ETag").unwrap().to_string();
+        let headers = [("Accept-Encoding", "br"), ("If-None-Match", etag.as_str())];
+        let resp = get(&files, "/files/a.txt", &headers);
+        assert_eq!(resp.status.code_num, 304);
+        assert_eq!(resp.headers.get("ETag"), Some(etag.as_str()));
+        assert_eq!(resp.headers.get("Vary"), Some("Accept-Encoding"));
+        assert!(!resp.compressible);
+
+        let files = files.precompressed(false);
+        let resp = get(&files, "/files/a.txt", &[("Accept-Encoding", "br")]);
+        let etag = resp.headers.get("ETag").unwrap().to_string();
+        let resp = get(&files, "/files/a.txt", &[("If-None-Match", etag.as_str())]);
+        assert_eq!(resp.status.code_num, 304);
+        assert!(
+            resp.replaced
+                .is_some_and(|r| matches!(r.content, Body::File(_)))
+        );
+        let resp = get(&files, "/files/a.txt", &[("Accept-Encoding", "br")]);
+        assert_eq!(resp.headers.get("Vary"), None);
+        assert_eq!(resp.headers.get("Content-Encoding"), None);
+        assert_eq!(content(resp), "plain");
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            content_type(Path::new("a/index.html")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            content_type(Path::new("app.JS")),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(content_type(Path::new("logo.png")), "image/png");
+        assert_eq!(content_type(Path::new("data")), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_html_escape() {
+        assert_eq!(
+            html_escape("<a href='x'>&</a>"),
+            "&lt;a href=&#39;x&#39;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn test_index_file() {
+        let (_dir, root) = temp_root("index");
+        let files = StaticFiles::new(root);
+
+        let resp = get(&files, "/files/docs/", &[]);
+        assert_eq!(resp.status.code_num, 200);
+        assert_eq!(
+            resp.headers.get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(content(resp), "<p>docs</p>");
+
+        let files = files.index_file(None);
+        assert_eq!(get(&files, "/files/docs/", &[]).status.code_num, 404);
+
+        assert_eq!(get(&files, "/files/missing.txt", &[]).status.code_num, 404);
+    }
+
+    #[test]
+    fn test_directory_redirect() {
+        let (_dir, root) = temp_root("redirect");
+        let files = StaticFiles::new(root);
+
+        let resp = get(&files, "/files/docs", &[]);
+        assert_eq!(resp.status.code_num, 301);
+        assert_eq!(resp.headers.get("Location"), Some("/files/docs/"));
+
+        let resp = get(&files, "/files/docs?a=1&b", &[]);
+        assert_eq!(resp.status.code_num, 301);
+        assert_eq!(resp.headers.get("Location"), Some("/files/docs/?a=1&b"));
+
+        let resp = get(&files, "/files/docs?a=\rb", &[]);
+        assert_eq!(resp.status.code_num, 400);
+    }
+
+    #[test]
+    fn test_listing() {
+        let (_dir, root) = temp_root("listing");
+        let files = StaticFiles::new(root);
+        assert_eq!(get(&files, "/files/list/", &[]).status.code_num, 404);
+
+        let files = files.listing(DirListing::Html);
+        let resp = get(&files, "/files/list/", &[]);
+        assert_eq!(resp.status.code_num, 200);
+        assert_eq!(
+            resp.headers.get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let html = content(resp);
+        assert!(html.contains("<title>Index of /files/list/</title>"));
+        assert!(html.contains(
+            "<li><a href=\"b%20c.txt\">b c.txt</a></li>\n<li><a href=\"sub/\">sub/</a></li>"
+        ));
+
+        let files = files.listing(DirListing::Json);
+        let resp = get(&files, "/files/list/", &[]);
+        assert_eq!(resp.headers.get("Content-Type"), Some("application/json"));
+        let json: serde_json::Value = serde_json::from_str(&content(resp)).unwrap();
+        let entries: Vec<_> = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["name"].as_str().unwrap(), e["type"].as_str().unwrap()))
+            .collect();
+        assert_eq!(entries, [("b c.txt", "file"), ("sub", "dir")]);
+        assert_eq!(json[0]["size"], 1);
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let (_dir, root) = temp_root("cache-control");
+        let files = StaticFiles::new(root);
+        let resp = get(&files, "/files/a.txt", &[]);
+        assert_eq!(resp.headers.get("Cache-Control"), None);
+
+        let files = files.cache_control("max-age=3600");
+        let resp = get(&files, "/files/a.txt", &[]);
+        assert_eq!(resp.headers.get("Cache-Control"), Some("max-age=3600"));
+        let resp = get(&files, "/files/docs/", &[]);
+        assert_eq!(resp.headers.get("Cache-Control"), Some("max-age=3600"));
+    }
+}
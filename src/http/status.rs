@@ -12,12 +12,68 @@ impl Status {
         code_num: 201,
         message: "Created",
     };
+    pub const NO_CONTENT: Status = Status {
+        code_num: 204,
+        message: "No Content",
+    };
+    pub const PARTIAL_CONTENT: Status = Status {
+        code_num: 206,
+        message: "Partial Content",
+    };
+    pub const MOVED_PERMANENTLY: Status = Status {
+        code_num: 301,
+        message: "Moved Permanently",
+    };
+    pub const NOT_MODIFIED: Status = Status {
+        code_num: 304,
+        message: "Not Modified",
+    };
     pub const BAD_REQUEST: Status = Status {
         code_num: 400,
         message: "Bad Request",
     };
+    pub const FORBIDDEN: Status = Status {
+        code_num: 403,
+        message: "Forbidden",
+    };
     pub const NOT_FOUND: Status = Status {
         code_num: 404,
         message: "Not Found",
     };
+    pub const METHOD_NOT_ALLOWED: Status = Status {
+        code_num: 405,
+        message: "Method Not Allowed",
+    };
+    pub const NOT_ACCEPTABLE: Status = Status {
+        code_num: 406,
+        message: "Not Acceptable",
+    };
+    pub const CONFLICT: Status = Status {
+        code_num: 409,
+        message: "Conflict",
+    };
+    pub const PRECONDITION_FAILED: Status = Status {
+        code_num: 412,
+        message: "Precondition Failed",
+    };
+    pub const CONTENT_TOO_LARGE: Status = Status {
+        code_num: 413,
+        message: "Content Too Large",
+    };
+    pub const UNSUPPORTED_MEDIA_TYPE: Status = Status {
+        code_num: 415,
+        message: "Unsupported Media Type",
+    };
+    pub const RANGE_NOT_SATISFIABLE: Status = Status {
+        code_num: 416,
+        message: "Range Not Satisfiable",
+    };
+    pub const INTERNAL_SERVER_ERROR: Status = Status {
+        code_num: 500,
+        message: "Internal Server Error",
+    };
+    pub const NOT_IMPLEMENTED: Status = Status {
+        code_num: 501,
+        message: "Not Implemented",
+    };
 }
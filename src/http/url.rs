@@ -0,0 +1,197 @@
+use anyhow::{Context, anyhow};
+use percent_encoding::percent_decode_str;
+
+/// Splits a request target into its path and (still encoded) query.
+/// The scheme and authority of an absolute-form target are dropped.
+pub fn split_target(target: &str) -> (&str, Option<&str>) {
+    let target = target
+        .strip_prefix("http://")
+        .or_else(|| target.strip_prefix("https://"))
+        .map(|rest| rest.find('/').map(|i| &rest[i..]).unwrap_or("/"))
+        .unwrap_or(target);
+
+    // A fragment is never sent by clients, but must not end up in the query
+    let target = target.split_once('#').map(|(t, _)| t).unwrap_or(target);
+
+    match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    }
+}
+
+/// Normalizes a request path (RFC 3986, section 6.2.2): percent-encoded unreserved
+/// characters are decoded, remaining escapes are uppercased and dot-segments removed.
+/// Other escapes like `%2F` are kept, so they don't change the path structure.
+///
+/// Fails on malformed escapes and on paths that don't decode to UTF-8.
+pub fn normalize_path(path: &str) -> anyhow::Result<String> {
+    if !path.starts_with('/') {
+        // asterisk-form, as in `OPTIONS *`
+        return Ok(path.to_string());
+    }
+
+    percent_decode_str(path)
+        .decode_utf8()
+        .context("Path is not valid UTF-8")?;
+
+    let mut normalized = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(pos) = rest.find('%') {
+        normalized.push_str(&rest[..pos]);
+
+        let hex = rest
+            .get(pos + 1..pos + 3)
+            .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or(anyhow!("Invalid percent-encoding in path"))?;
+        let b = u8::from_str_radix(hex, 16)?;
+
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            normalized.push(b as char);
+        } else {
+            normalized.push('%');
+            normalized.push_str(&hex.to_ascii_uppercase());
+        }
+        rest = &rest[pos + 3..];
+    }
+    normalized.push_str(rest);
+
+    Ok(remove_dot_segments(&normalized))
+}
+
+/// RFC 3986, section 5.2.4, for absolute paths.
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+
+    for segment in path[1..].split('/') {
+        trailing_slash = matches!(segment, "." | "..");
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+
+    let mut result = format!("/{}", segments.join("/"));
+    if trailing_slash && !result.ends_with('/') {
+        result.push('/');
+    }
+    result
+}
+
+/// Decodes a percent-encoded path segment; invalid UTF-8 is replaced.
+pub fn decode_segment(s: &str) -> String {
+    percent_decode_str(s).decode_utf8_lossy().into_owned()
+}
+
+/// Decoded `application/x-www-form-urlencoded` query parameters, in order.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Query {
+    raw: String,
+    params: Vec<(String, String)>,
+}
+
+impl Query {
+    pub fn parse(raw: &str) -> Self {
+        Self {
+            raw: raw.to_string(),
+            params: form_urlencoded::parse(raw.as_bytes())
+                .into_owned()
+                .collect(),
+        }
+    }
+
+    /// Returns the first value of the parameter.
+    pub fn get(&self, k: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(name, _)| name == k)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, k: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.params
+            .iter()
+            .filter(move |(name, _)| name == k)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    /// Deserializes the parameters into a struct with flat fields.
+    pub fn deserialize<T: serde::de::DeserializeOwned>(
+        &self,
+    ) -> Result<T, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_str(&self.raw)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+
+    #[test]
+    fn test_split_target() {
+        assert_eq!(split_target("/echo/abc?x=1"), ("/echo/abc", Some("x=1")));
+        assert_eq!(split_target("/echo/abc"), ("/echo/abc", None));
+        assert_eq!(split_target("/a?"), ("/a", Some("")));
+        assert_eq!(
+            split_target("http://example.com/a/b?c#d"),
+            ("/a/b", Some("c"))
+        );
+        assert_eq!(split_target("http://example.com"), ("/", None));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/a/./b/../c").unwrap(), "/a/c");
+        assert_eq!(normalize_path("/a/b/..").unwrap(), "/a/");
+        assert_eq!(normalize_path("/../../etc").unwrap(), "/etc");
+        assert_eq!(normalize_path("/%2e%2E/x").unwrap(), "/x");
+        assert_eq!(normalize_path("/%7euser/%2f%20").unwrap(), "/~user/%2F%20");
+        assert_eq!(normalize_path("/a//b/").unwrap(), "/a//b/");
+        assert_eq!(normalize_path("*").unwrap(), "*");
+
+        assert!(normalize_path("/a%2").is_err());
+        assert!(normalize_path("/a%zz").is_err());
+        assert!(normalize_path("/%C3%28").is_err());
+    }
+
+    #[test]
+    fn test_query() {
+        let q = Query::parse("tag=a&name=John+Doe&tag=b%26c&empty");
+
+        assert_eq!(q.get("name"), Some("John Doe"));
+        assert_eq!(q.get_all("tag").collect::<Vec<_>>(), vec!["a", "b&c"]);
+        assert_eq!(q.get("empty"), Some(""));
+        assert_eq!(q.get("missing"), None);
+    }
+
+    #[test]
+    fn test_query_deserialize() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Page {
+            offset: u32,
+            limit: Option<u32>,
+        }
+
+        let q = Query::parse("offset=10");
+        assert_eq!(
+            q.deserialize::<Page>().unwrap(),
+            Page {
+                offset: 10,
+                limit: None
+            }
+        );
+        assert!(Query::parse("offset=x").deserialize::<Page>().is_err());
+    }
+}
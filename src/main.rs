@@ -1,41 +1,66 @@
-mod concurrency;
-mod http;
-
-use crate::http::request::RequestContext;
-use crate::http::{Response, not_found, ok};
+use codecrafters_http_server::http;
+use http::header::HeaderMap;
 use http::method::Method;
-use http::server;
+use http::middleware::conditional::{
+    ConditionalMw, Validators, evaluate_preconditions, precondition_response,
+};
+use http::middleware::decompression::{BodyTooLarge, DecompressionMw};
+use http::request::RequestContext;
+use http::router::RouteError;
+use http::safe_path::SafeRoot;
+use http::server::Server;
+use http::static_files::StaticFiles;
 use http::status::Status;
-use std::collections::HashMap;
+use http::{Response, not_found, ok};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Tells apart the temporary files of concurrent uploads.
+static UPLOAD_ID: AtomicU64 = AtomicU64::new(0);
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    let dir = Arc::new(args.get(2).map(String::from));
+    let dir = Arc::new(
+        args.get(2)
+            .map(|d| SafeRoot::new(d).expect("Can't open files directory")),
+    );
 
-    let mut server = server::Server::from_tcp_addr("127.0.0.1:4221", 10).unwrap();
+    let mut server = Server::from_tcp_addr("127.0.0.1:4221", 10).unwrap();
 
-    server.add_handler(Method::GET, "/", Box::new(index));
-    server.add_handler(Method::GET, "/echo/<s>", Box::new(echo));
-    server.add_handler(Method::GET, "/user-agent", Box::new(user_agent));
+    server.add_middleware(Box::new(ConditionalMw::new()));
+    server.add_middleware(Box::new(DecompressionMw::new()));
 
-    let dir_clone = Arc::clone(&dir);
-    server.add_handler(
-        Method::GET,
-        "/files/<file>",
-        Box::new(move |r| get_file(r, dir_clone.as_ref())),
-    );
+    if let Err(e) = add_routes(&mut server, dir) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    server.run().unwrap();
+}
+
+fn add_routes(server: &mut Server, dir: Arc<Option<SafeRoot>>) -> Result<(), RouteError> {
+    server.add_handler(Method::GET, "/", Box::new(index))?;
+    server.add_handler(Method::GET, "/echo/<s>", Box::new(echo))?;
+    server.add_handler(Method::GET, "/user-agent", Box::new(user_agent))?;
+
+    let files = dir.as_ref().clone().map(StaticFiles::new);
+    server.mount(
+        "/files",
+        Box::new(move |r| match &files {
+            Some(f) => f.handle(r),
+            None => not_found(),
+        }),
+    )?;
 
-    let dir_clone = Arc::clone(&dir);
     server.add_handler(
         Method::POST,
         "/files/<file>",
-        Box::new(move |r| post_file(r, dir_clone.as_ref())),
-    );
+        Box::new(move |r| post_file(r, dir.as_ref())),
+    )?;
 
-    server.run().unwrap();
+    Ok(())
 }
 
 fn index(_r: &RequestContext) -> Response {
@@ -45,13 +70,13 @@ fn index(_r: &RequestContext) -> Response {
 fn echo(r: &RequestContext) -> Response {
     let s = r.get_var("s").unwrap();
 
-    let headers = HashMap::from([("Content-Type".to_string(), "text/plain".to_string())]);
+    let headers = HeaderMap::from([("Content-Type", "text/plain")]);
 
     Response::from_parts(Status::OK, headers, Some(s.as_bytes().to_vec()))
 }
 
 fn user_agent(r: &RequestContext) -> Response {
-    let headers = HashMap::from([("Content-Type".to_string(), "text/plain".to_string())]);
+    let headers = HeaderMap::from([("Content-Type", "text/plain")]);
 
     Response::from_parts(
         Status::OK,
@@ -60,7 +85,7 @@ fn user_agent(r: &RequestContext) -> Response {
     )
 }
 
-fn get_file(r: &RequestContext, dir: &Option<String>) -> Response {
+fn post_file(r: &RequestContext, dir: &Option<SafeRoot>) -> Response {
     let dir = match dir {
         Some(d) => d,
         None => return not_found(),
@@ -68,33 +93,68 @@ fn get_file(r: &RequestContext, dir: &Option<String>) -> Response {
 
     let file_name = r.get_var("file").unwrap();
 
-    let mut file_path = PathBuf::from(dir);
-    file_path.push(file_name);
-
-    match fs::read(file_path) {
-        Ok(content) => Response::from_parts(
-            Status::OK,
-            HashMap::from([(
-                "Content-Type".to_string(),
-                "application/octet-stream".to_string(),
-            )]),
-            Some(content),
-        ),
-        Err(_) => not_found(),
+    let path = match dir.resolve(file_name) {
+        Ok(p) => p,
+        Err(e) => return e.into(),
+    };
+
+    // If-Match lets clients avoid overwriting changes they haven't seen
+    let current = fs::metadata(&path)
+        .ok()
+        .filter(|m| m.is_file())
+        .map(|m| Validators::from_metadata(&m));
+    let precondition = evaluate_preconditions(r, current.as_ref());
+    if let Some(resp) = precondition_response(precondition, &HeaderMap::new()) {
+        return resp;
     }
-}
 
-fn post_file(r: &RequestContext, dir: &Option<String>) -> Response {
-    let dir = match dir {
-        Some(d) => d,
-        None => return not_found(),
+    // A directory can't be replaced by an upload
+    if path.is_dir() {
+        return Response::from_parts(Status::CONFLICT, HeaderMap::new(), None);
+    }
+    // The body is written next to the file and moved into place once it's
+    // complete, so a failed upload leaves the previous content as it was
+    let tmp = path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        std::process::id(),
+        UPLOAD_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    let internal_error = |e: io::Error| {
+        println!("error writing {}: {}", path.display(), e);
+        let _ = fs::remove_file(&tmp);
+        Response::from_parts(Status::INTERNAL_SERVER_ERROR, HeaderMap::new(), None)
     };
 
-    let file_name = r.get_var("file").unwrap();
-
-    let mut path = PathBuf::from(dir);
-    path.extend(Path::new(file_name));
+    let mut file = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)
+    {
+        Ok(f) => f,
+        Err(e) => return internal_error(e),
+    };
+    if let Err(e) = io::copy(&mut *r.body(), &mut file) {
+        drop(file);
+        let _ = fs::remove_file(&tmp);
+        let status = if BodyTooLarge::is(&e) {
+            Status::CONTENT_TOO_LARGE
+        } else {
+            Status::BAD_REQUEST
+        };
+        return Response::from_parts(status, HeaderMap::new(), None);
+    }
+    drop(file);
+    if let Err(e) = fs::rename(&tmp, &path) {
+        return internal_error(e);
+    }
 
-    fs::write(path, &r.request().content).unwrap();
-    Response::from_parts(Status::CREATED, HashMap::new(), None)
+    let mut headers = HeaderMap::new();
+    if let Some(etag) = fs::metadata(&path)
+        .ok()
+        .and_then(|m| Validators::from_metadata(&m).etag)
+    {
+        headers.insert("ETag", etag.to_string());
+    }
+    Response::from_parts(Status::CREATED, headers, None)
 }